@@ -0,0 +1,260 @@
+//! Generates rdma-rpc services from `.proto` files, see the README for how to use it
+
+use std::{fmt::Write, io, path::Path};
+
+use prost_build::{Method, Service};
+
+/// Generate the messages and services of `protos` into `OUT_DIR`, with the default settings
+pub fn compile_protos(
+    protos: &[impl AsRef<Path>],
+    includes: &[impl AsRef<Path>],
+) -> io::Result<()> {
+    Builder::new().compile_protos(protos, includes)
+}
+
+/// Settings of the generated code
+pub struct Builder {
+    runtime: String,
+    config: prost_build::Config,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            runtime: "::rdma_rpc_core".to_string(),
+            config: prost_build::Config::new(),
+        }
+    }
+
+    /// Path of the `rdma-rpc-core` crate in the generated code, for crates that rename it
+    pub fn runtime_crate(mut self, path: impl Into<String>) -> Self {
+        self.runtime = path.into();
+        self
+    }
+
+    /// The prost settings of the generated messages
+    pub fn config(mut self, config: prost_build::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn compile_protos(
+        mut self,
+        protos: &[impl AsRef<Path>],
+        includes: &[impl AsRef<Path>],
+    ) -> io::Result<()> {
+        self.config.service_generator(Box::new(ServiceGenerator {
+            runtime: self.runtime,
+        }));
+        self.config.compile_protos(protos, includes)
+    }
+}
+
+/// Generates a server trait, its handler and a client for every service
+struct ServiceGenerator {
+    runtime: String,
+}
+
+impl prost_build::ServiceGenerator for ServiceGenerator {
+    fn generate(&mut self, mut service: Service, buf: &mut String) {
+        // streaming rpcs are left out, the rest of the service is still usable
+        service.methods.retain(|method| {
+            let streaming = method.client_streaming || method.server_streaming;
+            if streaming {
+                println!(
+                    "cargo:warning={}.{} is a streaming rpc, which rdma-rpc-build doesn't support yet, skipped",
+                    service.proto_name, method.proto_name
+                );
+            }
+            !streaming
+        });
+        self.generate_trait(&service, buf);
+        self.generate_server(&service, buf);
+        self.generate_client(&service, buf);
+    }
+}
+
+impl ServiceGenerator {
+    fn generate_trait(&self, service: &Service, buf: &mut String) {
+        let runtime = &self.runtime;
+        service.comments.append_with_indent(0, buf);
+        writeln!(buf, "pub trait {}: Send + Sync {{", service.name).unwrap();
+        for method in &service.methods {
+            method.comments.append_with_indent(1, buf);
+            writeln!(
+                buf,
+                "    fn {}(&self, ctx: &{runtime}::context::RequestContext, request: {}) \
+                 -> ::core::result::Result<{}, ::prost::alloc::string::String>;",
+                method.name, method.input_type, method.output_type
+            )
+            .unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+    }
+
+    fn generate_server(&self, service: &Service, buf: &mut String) {
+        let runtime = &self.runtime;
+        let name = &service.name;
+        writeln!(
+            buf,
+            "/// Serves an implementation of [`{name}`], hand it to a server stub in an `Arc`
+pub struct {name}Server<S>(pub S);
+
+impl<S: {name}> {runtime}::server_stub::RpcHandler for {name}Server<S> {{
+    type Args = {runtime}::protobuf::ProtoCall;
+    type Resp = {runtime}::protobuf::ProtoReply;
+
+    fn handle(&self, call: Self::Args) -> Self::Resp {{
+        self.handle_with(&::core::default::Default::default(), call)
+    }}
+
+    fn handle_with(&self, ctx: &{runtime}::context::RequestContext, call: Self::Args) -> Self::Resp {{
+        match call.method.as_str() {{"
+        )
+        .unwrap();
+        for method in &service.methods {
+            writeln!(
+                buf,
+                "            {:?} => {runtime}::protobuf::dispatch(&call, |request| self.0.{}(ctx, request)),",
+                method_path(service, method),
+                method.name
+            )
+            .unwrap();
+        }
+        writeln!(
+            buf,
+            "            _ => {runtime}::protobuf::unimplemented(&call),
+        }}
+    }}
+}}"
+        )
+        .unwrap();
+    }
+
+    fn generate_client(&self, service: &Service, buf: &mut String) {
+        let runtime = &self.runtime;
+        let name = &service.name;
+        writeln!(
+            buf,
+            "/// Calls the [`{name}`] service over a client stub
+pub struct {name}Client {{
+    stub: {runtime}::client_stub::ClientStub,
+}}
+
+impl {name}Client {{
+    pub fn new(stub: {runtime}::client_stub::ClientStub) -> Self {{
+        Self {{ stub }}
+    }}
+
+    pub fn into_inner(self) -> {runtime}::client_stub::ClientStub {{
+        self.stub
+    }}"
+        )
+        .unwrap();
+        for method in &service.methods {
+            writeln!(buf).unwrap();
+            method.comments.append_with_indent(1, buf);
+            writeln!(
+                buf,
+                "    pub fn {}(&mut self, request: &{}) \
+                 -> ::core::result::Result<{}, {runtime}::error::Error> {{
+        {runtime}::protobuf::call(&mut self.stub, {:?}, request)
+    }}",
+                method.name,
+                method.input_type,
+                method.output_type,
+                method_path(service, method)
+            )
+            .unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+    }
+}
+
+/// `package.Service/Method`, which names a method on the wire
+fn method_path(service: &Service, method: &Method) -> String {
+    if service.package.is_empty() {
+        format!("{}/{}", service.proto_name, method.proto_name)
+    } else {
+        format!(
+            "{}.{}/{}",
+            service.package, service.proto_name, method.proto_name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_build::{Comments, Method, Service, ServiceGenerator as _};
+
+    use super::ServiceGenerator;
+
+    fn method(name: &str, proto_name: &str) -> Method {
+        Method {
+            name: name.to_string(),
+            proto_name: proto_name.to_string(),
+            comments: Comments::default(),
+            input_type: "HelloRequest".to_string(),
+            output_type: "HelloReply".to_string(),
+            input_proto_type: ".greeter.HelloRequest".to_string(),
+            output_proto_type: ".greeter.HelloReply".to_string(),
+            options: Default::default(),
+            client_streaming: false,
+            server_streaming: false,
+        }
+    }
+
+    #[test]
+    fn generate_service() {
+        let service = Service {
+            name: "Greeter".to_string(),
+            proto_name: "Greeter".to_string(),
+            package: "greeter".to_string(),
+            comments: Comments::default(),
+            methods: vec![method("say_hello", "SayHello"), method("say_bye", "SayBye")],
+            options: Default::default(),
+        };
+        let mut buf = String::new();
+        ServiceGenerator {
+            runtime: "::rdma_rpc_core".to_string(),
+        }
+        .generate(service, &mut buf);
+
+        assert!(buf.contains("pub trait Greeter: Send + Sync {"));
+        assert!(buf.contains("fn say_hello(&self, ctx: &::rdma_rpc_core::context::RequestContext, request: HelloRequest)"));
+        assert!(buf.contains(
+            "impl<S: Greeter> ::rdma_rpc_core::server_stub::RpcHandler for GreeterServer<S>"
+        ));
+        assert!(buf.contains("\"greeter.Greeter/SayBye\" => ::rdma_rpc_core::protobuf::dispatch(&call, |request| self.0.say_bye(ctx, request)),"));
+        assert!(buf.contains("pub fn say_hello(&mut self, request: &HelloRequest)"));
+        assert!(buf.contains("::rdma_rpc_core::protobuf::call(&mut self.stub, \"greeter.Greeter/SayHello\", request)"));
+    }
+
+    #[test]
+    fn skip_streaming_rpcs() {
+        let mut streaming = method("say_many", "SayMany");
+        streaming.server_streaming = true;
+        let service = Service {
+            name: "Greeter".to_string(),
+            proto_name: "Greeter".to_string(),
+            package: "greeter".to_string(),
+            comments: Comments::default(),
+            methods: vec![method("say_hello", "SayHello"), streaming],
+            options: Default::default(),
+        };
+        let mut buf = String::new();
+        ServiceGenerator {
+            runtime: "::rdma_rpc_core".to_string(),
+        }
+        .generate(service, &mut buf);
+
+        assert!(buf.contains("pub fn say_hello(&mut self, request: &HelloRequest)"));
+        assert!(!buf.contains("say_many"));
+    }
+}
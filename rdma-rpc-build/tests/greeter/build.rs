@@ -0,0 +1,3 @@
+fn main() {
+    rdma_rpc_build::compile_protos(&["proto/greeter.proto"], &["proto"]).unwrap();
+}
@@ -9,7 +9,7 @@ use rdma_rpc_core::{
     messages::QPInfo,
     server_stub::{RpcHandler, ServerStub},
     session::Session,
-    transport::Transport,
+    transport::{query_mtu, Transport},
 };
 use serde::{Deserialize, Serialize};
 use KRdmaKit::{services_user, QueuePairBuilder, UDriver};
@@ -86,7 +86,7 @@ impl KVRpcHandler {
 
 fn create_session_pair() -> (Session, Session) {
     let udriver = UDriver::create().unwrap();
-    let device = udriver.devices().get(0).unwrap();
+    let device = udriver.devices().first().unwrap();
     let context = device.open_context().unwrap();
 
     let qp1 = QueuePairBuilder::new(&context)
@@ -99,6 +99,7 @@ fn create_session_pair() -> (Session, Session) {
         gid: services_user::ibv_gid_wrapper::from(qp1.gid().unwrap()),
         qp_num: qp1.qp_num(),
         qkey: qp1.qkey(),
+        mtu: query_mtu(&context, 1).unwrap(),
     };
     let qp2 = QueuePairBuilder::new(&context)
         .build_ud()
@@ -110,6 +111,7 @@ fn create_session_pair() -> (Session, Session) {
         gid: services_user::ibv_gid_wrapper::from(qp2.gid().unwrap()),
         qp_num: qp2.qp_num(),
         qkey: qp2.qkey(),
+        mtu: query_mtu(&context, 1).unwrap(),
     };
 
     let tp1 = Transport::new_with_qp(qp1, Arc::clone(&context), qp_info2, 1).unwrap();
@@ -1,22 +1,742 @@
+use alloc::{format, string::ToString, vec::Vec};
+
 use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    context::Metadata,
+    error::Error,
+    messages::{Answer, AnswerHead, CallHeader, Reply, Request},
+    retry::{Jitter, RetryPolicy},
+    session::Session,
+    streaming::StreamCall,
+    utils::{now_micros, sleep_micros},
+};
 
-use crate::{error::Error, session::Session};
+/// How many times a unary call is sent at most by default, if it has a timeout
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// How a call, a batch or a notification is made
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    /// the call may run more than once, so the server doesn't keep its response for retries,
+    /// and a call that failed on the server is retried as well. Batches and notifications are
+    /// never retried.
+    pub idempotent: bool,
+    /// handed to the handler along with the args
+    pub metadata: Metadata,
+}
 
 pub struct ClientStub {
     session: Session,
+    /// id of the next request that is answered
+    next_id: u64,
+    /// a unary call is sent again if its response doesn't arrive within this
+    call_timeout: Option<u64>,
+    retry: RetryPolicy,
+    jitter: Jitter,
 }
 
 impl ClientStub {
     pub fn new(session: Session) -> Self {
-        Self { session }
+        Self {
+            session,
+            next_id: 0,
+            call_timeout: None,
+            retry: RetryPolicy::default(),
+            jitter: Jitter::new(),
+        }
+    }
+
+    /// Take the id of a new request, the answers to all requests before it are in or given up
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Send a unary call again if its response doesn't arrive within `timeout_micros`,
+    /// by default a call waits for its response forever
+    ///
+    /// The server handles a call at most once however many times it's sent,
+    /// a retry is answered with the response the server kept for it. Every copy carries the time
+    /// the client still waits for the call over all the attempts left, the server skips a copy
+    /// that waited longer than that in its queue, and a call given up after the last retry is
+    /// cancelled on the server.
+    pub fn set_call_timeout(&mut self, timeout_micros: Option<u64>) {
+        self.call_timeout = timeout_micros;
+    }
+
+    /// Send a unary call at most `attempts` times before it fails with [`Error::Timeout`]
+    pub fn set_max_attempts(&mut self, attempts: usize) {
+        self.retry.max_attempts = attempts.max(1);
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
     }
 
     pub fn sync_call<T: Serialize + Clone, R: DeserializeOwned + Clone>(
         &mut self,
         args: T,
     ) -> Result<R, Error> {
-        // remote call
-        self.session.send(args)?;
-        self.session.recv()
+        self.sync_call_with(args, CallOptions::default())
+    }
+
+    /// Make a unary call, retrying it by the retry policy
+    ///
+    /// A call that timed out is sent again, and so is an idempotent one that failed on the server,
+    /// see [`Error::is_retryable`]. The server runs a call at most once for all its copies unless
+    /// it's idempotent: the server doesn't keep the response of an idempotent call, so a retry may run it again.
+    pub fn sync_call_with<T: Serialize + Clone, R: DeserializeOwned + Clone>(
+        &mut self,
+        args: T,
+        options: CallOptions,
+    ) -> Result<R, Error> {
+        // the client waits for a response until every attempt timed out
+        let deadline = self
+            .call_timeout
+            .map(|timeout| now_micros() + self.retry.max_call_micros(timeout));
+        // calls are made one at a time, so the responses of all earlier calls are in
+        let id = self.take_id();
+        let mut call = CallHeader {
+            id,
+            acked: id,
+            timeout_micros: None,
+            idempotent: options.idempotent,
+            metadata: options.metadata,
+        };
+
+        // copies of the call whose answers haven't arrived, they are skipped by later calls
+        let mut unanswered = 0;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // a copy sent later has less time left, after the earlier attempts and the backoff
+            call.timeout_micros = deadline.map(|deadline| deadline.saturating_sub(now_micros()));
+            let err = match self.attempt(&call, args.clone(), &mut unanswered) {
+                Ok(resp) => return Ok(resp),
+                Err(err) => err,
+            };
+
+            if !err.is_retryable(call.idempotent) || attempt >= self.retry.max_attempts {
+                if unanswered > 0 {
+                    // the server may still be working on the call, which nobody waits for anymore
+                    if let Err(err) = self.session.send(Request::<T>::Cancel(call.id)) {
+                        warn!("failed to cancel call {}, {err}", call.id);
+                    }
+                }
+                return Err(err);
+            }
+            let backoff = self.retry.backoff_micros(attempt, self.jitter.next());
+            warn!("call {} failed, {err}, retry in {backoff} micros", call.id);
+            sleep_micros(backoff.min(u32::MAX as u64) as u32);
+        }
+    }
+
+    /// Send the call once and wait for an answer to any copy of it
+    ///
+    /// The server answers a call that outlived its deadline as expired, which fails as a timeout.
+    fn attempt<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+        call: &CallHeader,
+        args: T,
+        unanswered: &mut usize,
+    ) -> Result<R, Error> {
+        self.session.send(Request::Unary(call.clone(), args))?;
+        *unanswered += 1;
+        let reply = self
+            .recv_reply(call.id, self.call_timeout)?
+            .ok_or(Error::Timeout)?;
+        *unanswered -= 1;
+        match reply {
+            Reply::Done(resp) => Ok(resp),
+            Reply::Expired => Err(Error::Timeout),
+            Reply::Cancelled => Err(Error::Cancelled),
+            Reply::Rejected(reason) => Err(Error::Rejected(reason)),
+            Reply::Failed(reason) => Err(Error::Remote(reason)),
+            Reply::Batch(_) | Reply::Stream => Err(unexpected_reply(call.id)),
+        }
+    }
+
+    /// Receive the reply to request `id` within `timeout_micros`, skipping the answers to earlier requests
+    fn recv_reply<R: DeserializeOwned>(
+        &mut self,
+        id: u64,
+        timeout_micros: Option<u64>,
+    ) -> Result<Option<Reply<R>>, Error> {
+        let codec = self.session.codec();
+        let deadline = timeout_micros.map(|timeout| now_micros() + timeout);
+        loop {
+            let timeout = deadline.map(|deadline| deadline.saturating_sub(now_micros()));
+            let message = match self.session.recv_message_timeout(timeout)? {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            // the answer to an earlier call may hold a response of another type
+            let head: AnswerHead = message.decode(codec)?;
+            if head.id != id {
+                debug!("skip the answer to request {}", head.id);
+                continue;
+            }
+            return Ok(Some(message.decode::<Answer<R>>(codec)?.reply));
+        }
+    }
+
+    /// Send many requests in one message, the responses come back together in the same order
+    ///
+    /// A request fails on its own with [`Error::Remote`] if the server failed to handle it.
+    pub fn call_batch<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+        args: Vec<T>,
+    ) -> Result<Vec<Result<R, Error>>, Error> {
+        self.call_batch_with(args, CallOptions::default())
+    }
+
+    /// Send a batch along with the metadata of `options`, which every request of it shares
+    ///
+    /// The batch is sent once, it fails with [`Error::Timeout`] if its responses don't arrive
+    /// within the call timeout, and the server skips it if it waited longer than that in the queue.
+    pub fn call_batch_with<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+        args: Vec<T>,
+        options: CallOptions,
+    ) -> Result<Vec<Result<R, Error>>, Error> {
+        let header = self.header(options);
+        let id = header.id;
+        self.session.send(Request::Batch(header, args))?;
+        match self
+            .recv_reply(id, self.call_timeout)?
+            .ok_or(Error::Timeout)?
+        {
+            Reply::Batch(resps) => Ok(resps
+                .into_iter()
+                .map(|resp| resp.map_err(Error::Remote))
+                .collect()),
+            Reply::Expired => Err(Error::Timeout),
+            _ => Err(unexpected_reply(id)),
+        }
+    }
+
+    /// Send a request that gets no response, returns once the server received it
+    pub fn notify<T: Serialize + Clone>(&mut self, args: T) -> Result<(), Error> {
+        self.notify_with(args, CallOptions::default())
+    }
+
+    /// Send a notification along with the metadata of `options`,
+    /// the server skips it if it waited longer than the call timeout in the queue
+    pub fn notify_with<T: Serialize + Clone>(
+        &mut self,
+        args: T,
+        options: CallOptions,
+    ) -> Result<(), Error> {
+        let header = self.header(options);
+        self.session.send(Request::Notify(header, args))
+    }
+
+    /// The header of a batch or a notification, which is sent once
+    fn header(&mut self, options: CallOptions) -> CallHeader {
+        let id = self.take_id();
+        CallHeader {
+            id,
+            acked: id,
+            timeout_micros: self.call_timeout,
+            idempotent: options.idempotent,
+            metadata: options.metadata,
+        }
+    }
+
+    /// Start a streaming call, items can be sent and received in any order until it's finished
+    ///
+    /// A streaming call carries no metadata or deadline, and it's never cancelled or retried.
+    pub fn open_stream<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+    ) -> Result<StreamCall<'_, T, R>, Error> {
+        let id = self.take_id();
+        self.session.send(Request::<T>::Stream(id))?;
+        match self.recv_reply::<R>(id, None)?.ok_or(Error::Timeout)? {
+            Reply::Stream => Ok(StreamCall::new(&mut self.session)),
+            _ => Err(unexpected_reply(id)),
+        }
+    }
+
+    /// Send a single request and iterate over the stream of responses
+    pub fn server_streaming<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+        args: T,
+    ) -> Result<StreamCall<'_, T, R>, Error> {
+        let mut call = self.open_stream()?;
+        call.send(args)?;
+        call.close_send()?;
+        Ok(call)
+    }
+
+    /// Send a stream of requests and wait for the single response
+    pub fn client_streaming<T: Serialize + Clone, R: DeserializeOwned>(
+        &mut self,
+        args: impl IntoIterator<Item = T>,
+    ) -> Result<R, Error> {
+        let mut call = self.open_stream()?;
+        for arg in args {
+            call.send(arg)?;
+        }
+        call.close_send()?;
+        let resp = call
+            .recv()?
+            .ok_or_else(|| Error::Internal("stream closed without a response".to_string()))?;
+        call.finish()?;
+        Ok(resp)
+    }
+}
+
+fn unexpected_reply(id: u64) -> Error {
+    Error::Internal(format!("unexpected reply to request {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        sync::Arc,
+        vec,
+        vec::Vec,
+    };
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{
+        mpsc::{self, Receiver},
+        Mutex,
+    };
+
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::{CallOptions, ClientStub};
+    use crate::{
+        context::{Metadata, RequestContext},
+        error::Error,
+        retry::RetryPolicy,
+        server_stub::{RpcHandler, ServerStub},
+        session::Session,
+        streaming::StreamCall,
+        utils::{sleep_millis, tests::new_two_transport},
+    };
+
+    /// Serve `handler` in the background, returns the client of the session
+    fn serve<H: RpcHandler + 'static>(handler: Arc<H>) -> ClientStub
+    where
+        H::Args: DeserializeOwned + Clone + Send + 'static,
+        H::Resp: Serialize + Clone + Send + 'static,
+    {
+        let (tp1, tp2) = new_two_transport();
+        let server = ServerStub::new(Session::new(0, tp2), handler);
+        std::thread::spawn(move || server.serve());
+        ClientStub::new(Session::new(0, tp1))
+    }
+
+    /// Sums up numbers, and counts up to a number as a stream
+    struct CountHandler;
+
+    impl RpcHandler for CountHandler {
+        type Args = u64;
+        type Resp = u64;
+
+        fn handle(&self, arg: u64) -> u64 {
+            arg + 1
+        }
+
+        fn handle_stream(&self, call: &mut StreamCall<'_, u64, u64>) -> Result<(), Error> {
+            let mut sum = 0;
+            while let Some(n) = call.recv()? {
+                if n == u64::MAX {
+                    return Err(Error::Internal("no such number".into()));
+                }
+                // a zero asks for a stream of what was summed up so far
+                if n == 0 {
+                    for i in 0..sum {
+                        call.send(i)?;
+                    }
+                }
+                sum += n;
+            }
+            call.send(sum)
+        }
+    }
+
+    /// Adds up notified numbers, a call returns the total
+    #[derive(Default)]
+    struct SumHandler {
+        total: AtomicU64,
+    }
+
+    impl RpcHandler for SumHandler {
+        type Args = u64;
+        type Resp = u64;
+
+        fn handle(&self, _arg: u64) -> u64 {
+            self.total.load(Ordering::SeqCst)
+        }
+
+        fn handle_notify(&self, arg: u64) {
+            self.total.fetch_add(arg, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn batch_calls() {
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(0, tp1));
+        let mut server = ServerStub::new(Session::new(0, tp2), Arc::new(CountHandler));
+        server.set_batch_workers(4);
+        std::thread::spawn(move || server.serve());
+
+        let resps = client.call_batch::<u64, u64>((0..50).collect()).unwrap();
+        assert!(resps.into_iter().map(Result::unwrap).eq(1..51));
+
+        assert!(client.call_batch::<u64, u64>(vec![]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn undecodable_calls() {
+        let mut client = serve(Arc::new(CountHandler));
+        client.set_retry_policy(RetryPolicy::no_retry());
+
+        // a call the server can't decode is never answered
+        client.set_call_timeout(Some(10_000));
+        assert!(matches!(
+            client.sync_call::<u8, u64>(1),
+            Err(Error::Timeout)
+        ));
+
+        // the calls after it get their own answers
+        client.set_call_timeout(None);
+        assert_eq!(client.sync_call::<u64, u64>(5).unwrap(), 6);
+        let resps = client.call_batch::<u64, u64>(vec![7]).unwrap();
+        assert_eq!(
+            resps.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            [8]
+        );
+    }
+
+    /// Fails on zero, by an error or by a panic
+    struct FailHandler {
+        panics: bool,
+    }
+
+    impl RpcHandler for FailHandler {
+        type Args = u64;
+        type Resp = Result<u64, String>;
+
+        fn handle(&self, arg: u64) -> Result<u64, String> {
+            if arg == 0 {
+                assert!(!self.panics, "no zero");
+                return Err("no zero".into());
+            }
+            Ok(arg)
+        }
+    }
+
+    #[test]
+    fn failed_calls() {
+        type Resp = Result<u64, String>;
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(0, tp1));
+        let mut server = ServerStub::new(
+            Session::new(0, tp2),
+            Arc::new(FailHandler { panics: false }),
+        );
+        server.set_batch_workers(2);
+        std::thread::spawn(move || server.serve());
+
+        // a failed request doesn't fail the others
+        let resps = client.call_batch::<u64, Resp>(vec![1, 0, 3]).unwrap();
+        let resps: Vec<Resp> = resps.into_iter().map(Result::unwrap).collect();
+        assert_eq!(resps, vec![Ok(1), Err("no zero".into()), Ok(3)]);
+
+        // nor does a panic, of a unary call or of a batched one
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(0, tp1));
+        let mut server =
+            ServerStub::new(Session::new(0, tp2), Arc::new(FailHandler { panics: true }));
+        server.set_batch_workers(2);
+        std::thread::spawn(move || server.serve());
+
+        assert!(matches!(
+            client.sync_call::<u64, Resp>(0),
+            Err(Error::Remote(_))
+        ));
+        assert_eq!(client.sync_call::<u64, Resp>(1).unwrap(), Ok(1));
+        let resps = client.call_batch::<u64, Resp>(vec![1, 0, 3]).unwrap();
+        assert_eq!(*resps[0].as_ref().unwrap(), Ok(1));
+        assert!(matches!(resps[1], Err(Error::Remote(_))));
+        assert_eq!(*resps[2].as_ref().unwrap(), Ok(3));
+    }
+
+    /// Counts how many times it ran, a zero waits until the test lets it go
+    struct GateHandler {
+        runs: AtomicU64,
+        gate: Mutex<Receiver<()>>,
+    }
+
+    impl RpcHandler for GateHandler {
+        type Args = u64;
+        type Resp = u64;
+
+        fn handle(&self, arg: u64) -> u64 {
+            if arg == 0 {
+                self.gate.lock().unwrap().recv().unwrap();
+            }
+            self.runs.fetch_add(1, Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn retried_calls_run_once() {
+        let (open, gate) = mpsc::channel();
+        let handler = GateHandler {
+            runs: AtomicU64::new(0),
+            gate: Mutex::new(gate),
+        };
+        let mut client = serve(Arc::new(handler));
+
+        // every attempt times out while the handler waits
+        client.set_call_timeout(Some(10_000));
+        client.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::no_retry()
+        });
+        assert!(matches!(
+            client.sync_call::<u64, u64>(0),
+            Err(Error::Timeout)
+        ));
+
+        // the call runs once for all its copies, whose responses are skipped
+        open.send(()).unwrap();
+        client.set_call_timeout(None);
+        assert_eq!(client.sync_call::<u64, u64>(1).unwrap(), 1);
+        assert_eq!(
+            client.call_batch::<u64, u64>(vec![1]).unwrap()[0]
+                .as_ref()
+                .unwrap(),
+            &2
+        );
+    }
+
+    /// Panics on every other run, starting with the first
+    struct FlakyHandler {
+        runs: AtomicU64,
+    }
+
+    impl RpcHandler for FlakyHandler {
+        type Args = u64;
+        type Resp = u64;
+
+        fn handle(&self, arg: u64) -> u64 {
+            if self.runs.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+                panic!("flaky handler");
+            }
+            arg
+        }
+    }
+
+    #[test]
+    fn failed_idempotent_calls_retry() {
+        let handler = Arc::new(FlakyHandler {
+            runs: AtomicU64::new(0),
+        });
+        let mut client = serve(Arc::clone(&handler));
+        client.set_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::no_retry()
+        });
+
+        // an idempotent call runs again after the handler failed
+        let idempotent = CallOptions {
+            idempotent: true,
+            ..Default::default()
+        };
+        assert_eq!(client.sync_call_with::<u64, u64>(1, idempotent).unwrap(), 1);
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+
+        // any other call fails at once, it must not run twice
+        assert!(matches!(
+            client.sync_call::<u64, u64>(1),
+            Err(Error::Remote(_))
+        ));
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 3);
+    }
+
+    /// Counts the calls it ran to the end, a zero runs until it's cancelled,
+    /// notifications wait until the test lets them go
+    struct CancelHandler {
+        runs: AtomicU64,
+        cancelled: AtomicU64,
+        gate: Mutex<Receiver<()>>,
+    }
+
+    impl RpcHandler for CancelHandler {
+        type Args = u64;
+        type Resp = u64;
+
+        fn handle(&self, arg: u64) -> u64 {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            arg
+        }
+
+        fn handle_with(&self, ctx: &RequestContext, arg: u64) -> u64 {
+            if arg == 0 {
+                while !ctx.is_cancelled() {
+                    sleep_millis(1);
+                }
+                self.cancelled.fetch_add(1, Ordering::SeqCst);
+                return 0;
+            }
+            self.handle(arg)
+        }
+
+        fn handle_notify(&self, _arg: u64) {
+            self.gate.lock().unwrap().recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn abandoned_calls() {
+        let (open, gate) = mpsc::channel();
+        let handler = Arc::new(CancelHandler {
+            runs: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            gate: Mutex::new(gate),
+        });
+        let mut client = serve(handler.clone());
+        client.set_retry_policy(RetryPolicy::no_retry());
+
+        // a call given up is cancelled while it runs
+        client.set_call_timeout(Some(10_000));
+        assert!(matches!(
+            client.sync_call::<u64, u64>(0),
+            Err(Error::Timeout)
+        ));
+        client.set_call_timeout(None);
+        assert_eq!(client.sync_call::<u64, u64>(1).unwrap(), 1);
+        assert_eq!(handler.cancelled.load(Ordering::SeqCst), 1);
+
+        // so is a call given up after its retries, whose copies are queued behind it
+        client.set_call_timeout(Some(10_000));
+        client.set_max_attempts(3);
+        assert!(matches!(
+            client.sync_call::<u64, u64>(0),
+            Err(Error::Timeout)
+        ));
+        client.set_call_timeout(None);
+        assert_eq!(client.sync_call::<u64, u64>(2).unwrap(), 2);
+        assert_eq!(handler.cancelled.load(Ordering::SeqCst), 2);
+
+        // a call queued behind a slow one is skipped
+        client.set_max_attempts(1);
+        client.notify(0).unwrap();
+        client.set_call_timeout(Some(10_000));
+        assert!(matches!(
+            client.sync_call::<u64, u64>(3),
+            Err(Error::Timeout)
+        ));
+        open.send(()).unwrap();
+        client.set_call_timeout(None);
+        assert_eq!(client.sync_call::<u64, u64>(4).unwrap(), 4);
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 3);
+    }
+
+    /// Looks up a key in the metadata of the call, prefixed by the session id
+    struct MetadataHandler;
+
+    impl RpcHandler for MetadataHandler {
+        type Args = String;
+        type Resp = Option<String>;
+
+        fn handle(&self, _arg: String) -> Option<String> {
+            None
+        }
+
+        fn handle_with(&self, ctx: &RequestContext, arg: String) -> Option<String> {
+            let value = ctx.metadata().get(&arg)?;
+            Some(format!("{}:{value}", ctx.session_id()))
+        }
+    }
+
+    #[test]
+    fn call_metadata() {
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(7, tp1));
+        let server = ServerStub::new(Session::new(7, tp2), Arc::new(MetadataHandler));
+        std::thread::spawn(move || server.serve());
+
+        let mut metadata = Metadata::new();
+        metadata.insert("trace-id".to_string(), "42".to_string());
+        let options = CallOptions {
+            metadata,
+            ..Default::default()
+        };
+        let resp: Option<String> = client
+            .sync_call_with("trace-id".to_string(), options.clone())
+            .unwrap();
+        assert_eq!(resp.as_deref(), Some("7:42"));
+
+        // the requests of a batch share its metadata
+        let resps = client
+            .call_batch_with::<_, Option<String>>(vec!["trace-id".to_string(); 2], options)
+            .unwrap();
+        assert!(resps
+            .into_iter()
+            .all(|resp| resp.unwrap().as_deref() == Some("7:42")));
+
+        // metadata only goes with the call it's set for
+        let resp: Option<String> = client.sync_call("trace-id".to_string()).unwrap();
+        assert!(resp.is_none());
+    }
+
+    #[test]
+    fn notify_calls() {
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(0, tp1));
+        let server = ServerStub::new(Session::new(0, tp2), Arc::new(SumHandler::default()));
+        std::thread::spawn(move || server.serve());
+
+        for n in 1..=100u64 {
+            client.notify(n).unwrap();
+        }
+        // notifications are served in order before the call
+        assert_eq!(client.sync_call::<u64, u64>(0).unwrap(), 5050);
+    }
+
+    #[test]
+    fn streaming_calls() {
+        let (tp1, tp2) = new_two_transport();
+        let mut client = ClientStub::new(Session::new(0, tp1));
+        let server = ServerStub::new(Session::new(0, tp2), Arc::new(CountHandler));
+        std::thread::spawn(move || server.serve());
+
+        assert_eq!(client.sync_call::<u64, u64>(1).unwrap(), 2);
+
+        // client streaming
+        let sum: u64 = client.client_streaming(1..=100u64).unwrap();
+        assert_eq!(sum, 5050);
+
+        // server streaming, longer than the flow control window
+        let mut call = client.open_stream::<u64, u64>().unwrap();
+        call.send(100).unwrap();
+        call.send(0).unwrap();
+        call.close_send().unwrap();
+        let items: Vec<u64> = call.map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), 101);
+        assert!(items[..100].iter().copied().eq(0..100));
+
+        // an error closes the stream
+        let mut call = client.server_streaming::<u64, u64>(u64::MAX).unwrap();
+        assert!(matches!(call.recv(), Err(Error::Remote(_))));
+        assert!(call.recv().unwrap().is_none());
+        call.finish().unwrap();
+
+        // a call dropped halfway leaves the session usable
+        let mut call = client.server_streaming::<u64, u64>(100).unwrap();
+        drop(call.recv());
+        drop(call);
+        assert_eq!(client.sync_call::<u64, u64>(2).unwrap(), 3);
     }
 }
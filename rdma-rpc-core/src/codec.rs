@@ -0,0 +1,145 @@
+use alloc::{string::ToString, vec::Vec};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Turns the values a session sends into bytes and back
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// bincode with fixed size integers, the fastest one
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// postcard, compact with varint integers and usable in `no_std` peers
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        postcard::to_allocvec(value).map_err(|err| Error::DecodeEncode(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        postcard::from_bytes(bytes).map_err(|err| Error::DecodeEncode(err.to_string()))
+    }
+}
+
+/// JSON, readable when debugging and understood by peers not written in Rust
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(value).map_err(|err| Error::DecodeEncode(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(|err| Error::DecodeEncode(err.to_string()))
+    }
+}
+
+/// The codecs a session may use, both ends of a session agree on one when it's set up
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Postcard,
+    Json,
+}
+
+impl CodecKind {
+    pub const ALL: [CodecKind; 3] = [CodecKind::Bincode, CodecKind::Postcard, CodecKind::Json];
+
+    /// The first of the codecs `proposed` by one end that is `accepted` by the other
+    pub fn negotiate(proposed: &[CodecKind], accepted: &[CodecKind]) -> Option<CodecKind> {
+        proposed
+            .iter()
+            .copied()
+            .find(|codec| accepted.contains(codec))
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::Postcard => Postcard.encode(value),
+            CodecKind::Json => Json.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::Postcard => Postcard.decode(bytes),
+            CodecKind::Json => Json.decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        collections::BTreeMap,
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
+
+    use super::{Codec, CodecKind};
+    use crate::messages::{CallHeader, Request};
+
+    #[test]
+    fn codecs_round_trip() {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("trace-id".to_string(), "42".to_string());
+        let request = Request::Unary(
+            CallHeader {
+                id: 3,
+                acked: 2,
+                timeout_micros: Some(1000),
+                idempotent: false,
+                metadata,
+            },
+            vec![String::from("key"), String::new()],
+        );
+
+        for codec in CodecKind::ALL {
+            let bytes = codec.encode(&request).unwrap();
+            let Request::Unary(header, args) =
+                codec.decode::<Request<Vec<String>>>(&bytes).unwrap()
+            else {
+                panic!("{codec:?} decoded another kind of request");
+            };
+            assert_eq!(
+                (header.id, header.acked, header.timeout_micros),
+                (3, 2, Some(1000))
+            );
+            assert_eq!(header.metadata["trace-id"], "42");
+            assert_eq!(args, ["key", ""]);
+        }
+
+        assert_eq!(
+            CodecKind::negotiate(
+                &[CodecKind::Json, CodecKind::Bincode],
+                &[CodecKind::Bincode]
+            ),
+            Some(CodecKind::Bincode)
+        );
+        assert_eq!(
+            CodecKind::negotiate(&[CodecKind::Json], &[CodecKind::Postcard]),
+            None
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::net::SocketAddr;
+
+use crate::utils::now_micros;
+
+/// Key/value pairs a client sends along with a call, like a trace id or an auth token
+pub type Metadata = BTreeMap<String, String>;
+
+/// Who is on the other end of a session
+#[derive(Debug, Clone, Copy)]
+pub struct PeerInfo {
+    /// where the client connected from to set up the session
+    pub addr: Option<SocketAddr>,
+    pub lid: u32,
+    pub qp_num: u32,
+}
+
+/// What a handler knows about the call it's handling besides the args
+pub struct RequestContext {
+    session_id: u64,
+    peer: Option<PeerInfo>,
+    metadata: Metadata,
+    /// when the client stops waiting for the response, in `now_micros` of this end
+    deadline: Option<u64>,
+    /// set when the client cancels the call
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RequestContext {
+    /// The context of a call received just now, whose client waits `timeout_micros` at most
+    pub(crate) fn new(
+        session_id: u64,
+        peer: Option<PeerInfo>,
+        metadata: Metadata,
+        timeout_micros: Option<u64>,
+    ) -> Self {
+        Self {
+            session_id,
+            peer,
+            metadata,
+            deadline: timeout_micros.map(|timeout| now_micros() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Cancel the call with `flag`, which all copies of the call share
+    pub(crate) fn with_cancel_flag(self, flag: Arc<AtomicBool>) -> Self {
+        Self {
+            cancelled: flag,
+            ..self
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// The client that made the call, if the server stub was told
+    pub fn peer(&self) -> Option<&PeerInfo> {
+        self.peer.as_ref()
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// How long the client still waits for the response, `None` if it waits forever
+    pub fn remaining_micros(&self) -> Option<u64> {
+        self.deadline
+            .map(|deadline| deadline.saturating_sub(now_micros()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining_micros() == Some(0)
+    }
+
+    /// Whether the client has cancelled the call, a long-running handler should check it now and then
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RequestContext {
+    /// The context of a call without a session, metadata or deadline
+    fn default() -> Self {
+        Self::new(0, None, Metadata::new(), None)
+    }
+}
@@ -0,0 +1,145 @@
+use alloc::collections::VecDeque;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    error::Error,
+    messages::{kind, PacketBuf, RecvBuf, PACKET_HEADER_BYTES},
+    transport::PacketTransport,
+};
+
+/// Unreliable messaging over a transport, every message is a single packet
+///
+/// Datagrams are never acked or retransmitted, so they may be lost, duplicated or reordered.
+/// It suits loss-tolerant traffic where a late message is as useless as a lost one.
+/// A message must fit in one packet, see `max_payload_bytes`.
+///
+/// The channel takes every packet its transport receives. On a [`SharedTransport`] it's registered
+/// with an id of its own, so the demux keeps its datagrams apart from the packets of sessions.
+///
+/// [`SharedTransport`]: crate::demux::SharedTransport
+pub struct DatagramChannel<P> {
+    transport: P,
+    id: u64,
+    /// seq of the next datagram, only for debugging since datagrams aren't ordered
+    seq: u64,
+    /// packets received but not consumed yet, they hold their recv buffers until then
+    pending: VecDeque<RecvBuf>,
+}
+
+impl<P: PacketTransport> DatagramChannel<P> {
+    /// `id` plays the role of a session id, it picks the inbox on a shared transport and must not
+    /// be the id of a session
+    pub fn new(id: u64, transport: P) -> Self {
+        Self {
+            transport,
+            id,
+            seq: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The largest serialized message a datagram can carry
+    pub fn max_payload_bytes(&self) -> usize {
+        self.transport.max_data_bytes()
+    }
+
+    /// Serialize `value` straight into a send slot and post it, without waiting for anything
+    pub fn send_datagram<T: Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let size = bincode::serialized_size(value)? as usize;
+        if size > self.max_payload_bytes() {
+            return Err(Error::TooLarge(size, self.max_payload_bytes()));
+        }
+
+        let slot = self.transport.alloc_send_slot()?;
+        let data = unsafe { slot.slot().as_mut_slice() };
+        bincode::serialize_into(
+            &mut data[PACKET_HEADER_BYTES..PACKET_HEADER_BYTES + size],
+            value,
+        )?;
+        let buf = PacketBuf::seal(slot, kind::DATAGRAM, self.seq, self.id, size);
+        self.seq += 1;
+
+        // the slot is freed once the send completes
+        self.transport.post_bufs(&[&buf])
+    }
+
+    /// Block until a datagram is received
+    pub fn recv_datagram<T: DeserializeOwned>(&mut self) -> Result<T, Error> {
+        loop {
+            if let Some(value) = self.recv_datagram_timeout(None)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Block until a datagram is received or `timeout_micros` elapsed, returns `None` on timeout
+    pub fn recv_datagram_timeout<T: DeserializeOwned>(
+        &mut self,
+        timeout_micros: Option<u64>,
+    ) -> Result<Option<T>, Error> {
+        loop {
+            if let Some(value) = self.pop()? {
+                return Ok(Some(value));
+            }
+            let packets = self.transport.recv_timeout(timeout_micros)?;
+            if packets.is_empty() {
+                return Ok(None);
+            }
+            self.pending.extend(packets);
+        }
+    }
+
+    /// Return a datagram received so far without blocking, if any
+    pub fn try_recv_datagram<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        if self.pending.is_empty() {
+            let packets = self.transport.try_recv()?;
+            self.pending.extend(packets);
+        }
+        self.pop()
+    }
+
+    /// Deserialize the first pending datagram, packets of other kinds don't belong to the channel
+    fn pop<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        while let Some(packet) = self.pending.pop_front() {
+            if !packet.is_datagram() {
+                warn!("drop non-datagram packet, seq: {}", packet.seq());
+                continue;
+            }
+            debug!("recv datagram {}, size: {}", packet.seq(), packet.len());
+            return Ok(Some(bincode::deserialize(&packet)?));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::DatagramChannel;
+    use crate::{
+        error::Error,
+        utils::tests::{new_random_data, new_two_transport},
+    };
+
+    #[test]
+    fn datagram_works() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut d1, mut d2) = (DatagramChannel::new(0, tp1), DatagramChannel::new(0, tp2));
+
+        assert!(d2.try_recv_datagram::<u64>().unwrap().is_none());
+
+        let data = new_random_data(64);
+        d1.send_datagram(&data).unwrap();
+        assert_eq!(d2.recv_datagram::<Vec<u8>>().unwrap(), data);
+
+        // a payload that doesn't fit in one packet is rejected
+        let data = new_random_data(d1.max_payload_bytes());
+        assert!(matches!(
+            d1.send_datagram(&data),
+            Err(Error::TooLarge(_, _))
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    sync::Arc,
+    vec::Vec,
+};
+
+use spin::Mutex;
+use tracing::{debug, warn};
+use KRdmaKit::{context::Context, DatagramEndpoint};
+
+use crate::{
+    error::Error,
+    messages::{Packet, PacketBuf, QPInfo, RecvBuf},
+    recv_pool::SharedRecvPool,
+    slab::{OwnedSlot, PoolStats},
+    transport::{
+        max_data_bytes, new_endpoint, new_ud_qp, query_mtu, PacketTransport, QpConfig, UdQueuePair,
+    },
+    utils::Backoff,
+    wait::{poll_until, CqEvents, WaitMode},
+};
+
+/// A well-known UD qp shared by many sessions
+///
+/// UD is connectionless, so a server only needs one (or a few) qps no matter how many clients it serves.
+/// Every packet carries its session id, incoming packets are demultiplexed to the inbox of their session.
+/// Whichever session polls the recv cq delivers the packets of all other sessions as well.
+pub struct SharedTransport {
+    context: Arc<Context>,
+    port: u8,
+    inner: Mutex<SharedInner>,
+}
+
+struct SharedInner {
+    ud: UdQueuePair,
+    /// session id to packets received but not yet consumed by the session
+    inboxes: BTreeMap<u64, VecDeque<RecvBuf>>,
+    /// packets an inbox holds at most, those of a session that doesn't poll are dropped beyond it
+    inbox_limit: usize,
+}
+
+impl SharedInner {
+    /// Poll the recv cq on behalf of session `polling` and dispatch packets to sessions
+    ///
+    /// Packets of other sessions are copied out of their recv buffers, a session that isn't
+    /// receiving must not hold on to the buffers of the shared qp. A full inbox drops its packets,
+    /// the peer of the session retransmits them once the session polls again.
+    fn poll(&mut self, polling: u64) -> Result<(), Error> {
+        for mut packet in self.ud.poll_recv()? {
+            match self.inboxes.get_mut(&packet.session_id()) {
+                Some(inbox) if packet.session_id() == polling => inbox.push_back(packet),
+                Some(inbox) if inbox.len() >= self.inbox_limit => warn!(
+                    "drop packet of session {}, seq: {}, its inbox is full",
+                    packet.session_id(),
+                    packet.seq()
+                ),
+                Some(inbox) => {
+                    packet.detach();
+                    inbox.push_back(packet)
+                }
+                None => warn!(
+                    "drop packet of unknown session {}, seq: {}",
+                    packet.session_id(),
+                    packet.seq()
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SharedTransport {
+    pub fn new(context: Arc<Context>, port: u8) -> Result<Arc<Self>, Error> {
+        Self::new_with_config(context, port, QpConfig::default(), None)
+    }
+
+    /// Create a shared qp whose recv buffers are borrowed from `pool` rather than owned
+    pub fn new_with_recv_pool(
+        context: Arc<Context>,
+        port: u8,
+        pool: Arc<SharedRecvPool>,
+    ) -> Result<Arc<Self>, Error> {
+        Self::new_with_config(context, port, QpConfig::default(), Some(pool))
+    }
+
+    /// Create a shared qp with `config`, whose recv buffers are borrowed from `recv_pool` if any
+    pub fn new_with_config(
+        context: Arc<Context>,
+        port: u8,
+        config: QpConfig,
+        recv_pool: Option<Arc<SharedRecvPool>>,
+    ) -> Result<Arc<Self>, Error> {
+        let qp = new_ud_qp(&context, &config)?;
+        let mtu = query_mtu(&context, port)?;
+        let ud = match recv_pool {
+            Some(pool) => {
+                UdQueuePair::new_with_recv_pool(qp, Arc::clone(&context), mtu, &config, pool)?
+            }
+            None => UdQueuePair::new(qp, Arc::clone(&context), mtu, &config)?,
+        };
+        Ok(Self::with_ud(context, port, ud, config.pool_size))
+    }
+
+    fn with_ud(context: Arc<Context>, port: u8, ud: UdQueuePair, inbox_limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            context,
+            port,
+            inner: Mutex::new(SharedInner {
+                ud,
+                inboxes: BTreeMap::new(),
+                inbox_limit,
+            }),
+        })
+    }
+
+    pub fn qp_info(&self) -> QPInfo {
+        self.inner.lock().ud.qp_info()
+    }
+
+    /// Register a session on this qp, replies of the session will be sent to `peer`
+    pub fn open_session(
+        self: &Arc<Self>,
+        session_id: u64,
+        peer: QPInfo,
+    ) -> Result<SessionPort, Error> {
+        let mut inner = self.inner.lock();
+        let path_mtu = inner.ud.mtu().min(peer.mtu);
+        let endpoint = new_endpoint(&self.context, self.port, peer)?;
+
+        if inner.inboxes.contains_key(&session_id) {
+            return Err(Error::Internal(format!(
+                "session {session_id} already exists"
+            )));
+        }
+        inner.inboxes.insert(session_id, VecDeque::new());
+        debug!(
+            "session {session_id} registered, {} sessions in total",
+            inner.inboxes.len()
+        );
+
+        Ok(SessionPort {
+            shared: Arc::clone(self),
+            session_id,
+            endpoint,
+            path_mtu,
+            events: inner.ud.events().cloned(),
+            wait_mode: WaitMode::default(),
+        })
+    }
+
+    /// Number of sessions currently registered
+    pub fn sessions(&self) -> usize {
+        self.inner.lock().inboxes.len()
+    }
+
+    pub fn send_pool_stats(&self) -> PoolStats {
+        self.inner.lock().ud.send_pool_stats()
+    }
+
+    /// Allow the send pool to grow up to `limit` slots when it's exhausted
+    pub fn set_send_pool_limit(&self, limit: usize) {
+        self.inner.lock().ud.set_send_pool_limit(limit)
+    }
+}
+
+/// The view of a [`SharedTransport`] owned by a single session
+///
+/// It keeps the address handle of the peer, and unregisters the session when dropped.
+pub struct SessionPort {
+    shared: Arc<SharedTransport>,
+    session_id: u64,
+    endpoint: DatagramEndpoint,
+    /// the smaller one of the shared qp's mtu and the peer's mtu
+    path_mtu: u64,
+    /// events of the shared qp, waited on without holding its lock
+    events: Option<CqEvents>,
+    wait_mode: WaitMode,
+}
+
+impl SessionPort {
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// The mtu negotiated with the peer
+    pub fn path_mtu(&self) -> u64 {
+        self.path_mtu
+    }
+
+    pub fn set_wait_mode(&mut self, mode: WaitMode) {
+        self.wait_mode = mode;
+    }
+}
+
+impl PacketTransport for SessionPort {
+    fn send_burst(&mut self, packets: Vec<Packet>) -> Result<(), Error> {
+        let len = packets.len();
+        let mut left_to_be_sent: usize = len;
+        let mut backoff = Backoff::new();
+
+        loop {
+            // release the lock between attempts so other sessions can make progress
+            let left = self.shared.inner.lock().ud.send(
+                &self.endpoint,
+                self.path_mtu,
+                &packets[len - left_to_be_sent..],
+            )?;
+            if left == 0 {
+                break Ok(());
+            }
+
+            // the send pool is exhausted, wait for send completions to free some slots
+            if left < left_to_be_sent {
+                backoff.reset();
+            } else {
+                backoff.snooze();
+            }
+            left_to_be_sent = left;
+        }
+    }
+
+    fn recv_timeout(&mut self, timeout_micros: Option<u64>) -> Result<Vec<RecvBuf>, Error> {
+        let events = self.events.clone();
+        let packets = poll_until(self.wait_mode, events.as_ref(), timeout_micros, || {
+            let packets = self.try_recv()?;
+            Ok((!packets.is_empty()).then_some(packets))
+        })?;
+        Ok(packets.unwrap_or_default())
+    }
+
+    fn try_recv(&mut self) -> Result<Vec<RecvBuf>, Error> {
+        let mut inner = self.shared.inner.lock();
+        inner.poll(self.session_id)?;
+        let inbox = inner.inboxes.get_mut(&self.session_id).ok_or_else(|| {
+            Error::Internal(format!("session {} isn't registered", self.session_id))
+        })?;
+        Ok(inbox.drain(..).collect())
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        max_data_bytes(self.path_mtu)
+    }
+
+    fn try_alloc_send_slot(&mut self) -> Result<Option<OwnedSlot>, Error> {
+        self.shared.inner.lock().ud.alloc_send_slot()
+    }
+
+    fn post_bufs(&mut self, bufs: &[&PacketBuf]) -> Result<(), Error> {
+        self.shared
+            .inner
+            .lock()
+            .ud
+            .post_bufs(&self.endpoint, self.path_mtu, bufs)
+    }
+
+    fn cq_events(&self) -> Option<CqEvents> {
+        self.events.clone()
+    }
+}
+
+impl Drop for SessionPort {
+    fn drop(&mut self) {
+        self.shared.inner.lock().inboxes.remove(&self.session_id);
+        debug!("session {} unregistered", self.session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use super::SharedTransport;
+    use crate::{
+        datagram::DatagramChannel,
+        messages::Packet,
+        session::Session,
+        transport::{PacketTransport, Transport, DEFAULT_POOL_SIZE},
+        utils::{
+            now_micros,
+            tests::{new_random_data, new_test_context, new_test_qp},
+        },
+    };
+
+    #[test]
+    fn demux_two_sessions() {
+        let context = new_test_context();
+        let shared = SharedTransport::new(Arc::clone(&context), 1).unwrap();
+
+        let mut c1 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared.qp_info(),
+            1,
+        )
+        .unwrap();
+        let mut c2 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared.qp_info(),
+            1,
+        )
+        .unwrap();
+        let mut s1 = shared.open_session(1, c1.qp_info()).unwrap();
+        let mut s2 = shared.open_session(2, c2.qp_info()).unwrap();
+        assert_eq!(shared.sessions(), 2);
+
+        // packets are delivered to the session they belong to
+        let data1 = new_random_data(64);
+        let data2 = new_random_data(64);
+        c2.send_burst(vec![Packet::new(0, 2, data2.clone())])
+            .unwrap();
+        c1.send_burst(vec![Packet::new(0, 1, data1.clone())])
+            .unwrap();
+        assert_eq!(s1.recv().unwrap().remove(0).into_data(), data1);
+        assert_eq!(s2.recv().unwrap().remove(0).into_data(), data2);
+
+        // replies are sent to the peer of each session
+        s2.send_burst(vec![Packet::new(0, 2, data1.clone())])
+            .unwrap();
+        assert_eq!(c2.recv().unwrap().remove(0).into_data(), data1);
+
+        drop(s1);
+        assert_eq!(shared.sessions(), 1);
+    }
+
+    #[test]
+    // the inbox of a session that doesn't poll stops growing once it's full
+    fn demux_full_inbox() {
+        let context = new_test_context();
+        let shared = SharedTransport::new(Arc::clone(&context), 1).unwrap();
+
+        let mut c2 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared.qp_info(),
+            1,
+        )
+        .unwrap();
+        let mut s1 = shared.open_session(1, c2.qp_info()).unwrap();
+        let mut s2 = shared.open_session(2, c2.qp_info()).unwrap();
+
+        for round in 0..4 {
+            let packets = (0..DEFAULT_POOL_SIZE as u64 / 2)
+                .map(|seq| Packet::new(round * 32 + seq, 2, new_random_data(64)))
+                .collect();
+            c2.send_burst(packets).unwrap();
+            // session 1 polls the shared qp, which delivers the packets of session 2 to its inbox
+            let start = now_micros();
+            while now_micros() - start < 10_000 {
+                assert!(s1.try_recv().unwrap().is_empty());
+            }
+        }
+        assert_eq!(s2.try_recv().unwrap().len(), DEFAULT_POOL_SIZE);
+    }
+
+    #[test]
+    fn demux_session_and_datagrams() {
+        let context = new_test_context();
+        let shared = SharedTransport::new(Arc::clone(&context), 1).unwrap();
+
+        let c1 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared.qp_info(),
+            1,
+        )
+        .unwrap();
+        let c2 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared.qp_info(),
+            1,
+        )
+        .unwrap();
+        // the datagram channel is registered with an id of its own
+        let mut s1 = Session::new(1, shared.open_session(1, c1.qp_info()).unwrap());
+        let mut d2 = DatagramChannel::new(2, shared.open_session(2, c2.qp_info()).unwrap());
+        let (mut c1, mut c2) = (Session::new(1, c1), DatagramChannel::new(2, c2));
+
+        let data1 = new_random_data(4096);
+        let data1_c = data1.clone();
+        let data2 = new_random_data(64);
+        let data2_c = data2.clone();
+        let handle = std::thread::spawn(move || {
+            c2.send_datagram(&data2).unwrap();
+            c1.send(data1).unwrap();
+        });
+
+        // neither eats the packets of the other, whichever of them polls the qp
+        assert_eq!(d2.recv_datagram::<Vec<u8>>().unwrap(), data2_c);
+        assert_eq!(s1.recv::<Vec<u8>>().unwrap(), data1_c);
+        handle.join().unwrap();
+    }
+}
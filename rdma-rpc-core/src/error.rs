@@ -4,6 +4,10 @@ use thiserror_no_std::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error("the call was cancelled")]
+    Cancelled,
+    #[error("message checksum {0:#x} doesn't match its data, whose checksum is {1:#x}")]
+    Checksum(u32, u32),
     #[error("failed to connect to the server")]
     Connect,
     #[error("failed to encode rpc args")]
@@ -14,8 +18,32 @@ pub enum Error {
     Internal(String),
     #[error("receive error")]
     Receive,
+    #[error("remote call failed, {0}")]
+    Remote(String),
+    #[error("call rejected, {0}")]
+    Rejected(String),
     #[error("timeout")]
     Timeout,
+    #[error("message of {0} bytes is larger than the maximum of {1} bytes")]
+    TooLarge(usize, usize),
+}
+
+impl Error {
+    /// Whether a call that failed with this error may be sent again, `idempotent` if it may run more than once
+    ///
+    /// - a timeout, also of a call the server was too busy to get to before its deadline, is retried:
+    ///   the server runs a call that isn't idempotent at most once for all its copies.
+    /// - a failed handler or a rejection by an interceptor, e.g. of an overloaded server, is retried
+    ///   only if the call is idempotent, the server doesn't keep the failure for later copies.
+    /// - other errors are never retried, a cancelled call is given up, encoding errors or limits
+    ///   would fail again, and transport errors, e.g. a reset session, leave the session broken.
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            Self::Timeout => true,
+            Self::Remote(_) | Self::Rejected(_) => idempotent,
+            _ => false,
+        }
+    }
 }
 
 impl From<bincode::Error> for Error {
@@ -0,0 +1,356 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    context::{Metadata, RequestContext},
+    error::Error,
+};
+
+/// What an interceptor does with a request
+pub enum Intercept<R> {
+    /// pass the request on to the next interceptor, and to the handler after the last one
+    Continue,
+    /// answer the request with this response instead of passing it on
+    Respond(R),
+    /// fail the request with this reason instead of passing it on
+    Reject(String),
+}
+
+/// Runs around the handler of every request a server stub serves, for logging, auth checks,
+/// metrics and the like
+///
+/// Streaming calls aren't intercepted, their items are sent and received by the handler itself.
+pub trait ServerInterceptor<T, R>: Send + Sync {
+    /// Look at or change the args before they are passed on
+    fn on_request(&self, _ctx: &RequestContext, _args: &mut T) -> Intercept<R> {
+        Intercept::Continue
+    }
+
+    /// Look at or change the response on its way back, it's never called for a notification
+    fn on_response(&self, _ctx: &RequestContext, _resp: &mut R) {}
+}
+
+/// Runs around every call a client makes, and every request of a batch or notification it sends
+///
+/// Streaming calls aren't intercepted, their items are sent and received by the caller itself.
+pub trait ClientInterceptor<T, R>: Send + Sync {
+    /// Look at or change the args and the metadata of the call before they are passed on,
+    /// the requests of a batch share its metadata
+    fn on_request(&self, _args: &mut T, _metadata: &mut Metadata) -> Intercept<R> {
+        Intercept::Continue
+    }
+
+    /// Look at or change the outcome of the call on its way back, it's never called for a notification
+    fn on_response(&self, _result: &mut Result<R, Error>) {}
+}
+
+/// Server interceptors in the order requests pass them, responses pass them in reverse
+///
+/// An interceptor that answers a request itself sends the response back through the interceptors
+/// before it, a rejected request isn't seen by any of them again.
+pub struct ServerChain<T, R> {
+    interceptors: Vec<Arc<dyn ServerInterceptor<T, R>>>,
+}
+
+impl<T, R> ServerChain<T, R> {
+    pub fn push(&mut self, interceptor: Arc<dyn ServerInterceptor<T, R>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Pass a request through the chain to `handle`, and its response back
+    pub(crate) fn call(
+        &self,
+        ctx: &RequestContext,
+        mut args: T,
+        handle: impl FnOnce(T) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let mut passed = self.interceptors.len();
+        let mut result = None;
+        for (i, interceptor) in self.interceptors.iter().enumerate() {
+            match interceptor.on_request(ctx, &mut args) {
+                Intercept::Continue => continue,
+                Intercept::Respond(resp) => result = Some(Ok(resp)),
+                Intercept::Reject(reason) => return Err(reason),
+            }
+            passed = i;
+            break;
+        }
+
+        let mut resp = result.unwrap_or_else(|| handle(args))?;
+        for interceptor in self.interceptors[..passed].iter().rev() {
+            interceptor.on_response(ctx, &mut resp);
+        }
+        Ok(resp)
+    }
+
+    /// Pass a notification through the chain to `handle`, unless an interceptor takes it
+    pub(crate) fn notify(&self, ctx: &RequestContext, mut args: T, handle: impl FnOnce(T)) {
+        for interceptor in &self.interceptors {
+            if !matches!(interceptor.on_request(ctx, &mut args), Intercept::Continue) {
+                return;
+            }
+        }
+        handle(args)
+    }
+}
+
+impl<T, R> Default for ServerChain<T, R> {
+    fn default() -> Self {
+        Self {
+            interceptors: Vec::new(),
+        }
+    }
+}
+
+impl<T, R> Clone for ServerChain<T, R> {
+    fn clone(&self) -> Self {
+        Self {
+            interceptors: self.interceptors.clone(),
+        }
+    }
+}
+
+/// Client interceptors in the order calls pass them, outcomes pass them in reverse
+///
+/// An interceptor that answers or rejects a call itself sends the outcome back through
+/// the interceptors before it, and the call is never sent.
+pub struct ClientChain<T, R> {
+    interceptors: Vec<Arc<dyn ClientInterceptor<T, R>>>,
+}
+
+impl<T, R> ClientChain<T, R> {
+    pub fn push(&mut self, interceptor: Arc<dyn ClientInterceptor<T, R>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Pass a call through the chain to `send`, and its outcome back
+    pub fn call(
+        &self,
+        mut args: T,
+        mut metadata: Metadata,
+        send: impl FnOnce(T, Metadata) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let (passed, result) = self.request(&mut args, &mut metadata);
+        let mut result = result.unwrap_or_else(|| send(args, metadata));
+        self.respond(passed, &mut result);
+        result
+    }
+
+    /// Pass the requests of a batch through the chain one by one, those no interceptor took up
+    /// are sent together by `send`, and every outcome goes back through the interceptors its request passed
+    pub fn call_batch(
+        &self,
+        args: Vec<T>,
+        mut metadata: Metadata,
+        send: impl FnOnce(Vec<T>, Metadata) -> Result<Vec<Result<R, Error>>, Error>,
+    ) -> Result<Vec<Result<R, Error>>, Error> {
+        let mut taken = Vec::with_capacity(args.len());
+        let mut sent = Vec::new();
+        for mut args in args {
+            let (passed, result) = self.request(&mut args, &mut metadata);
+            if result.is_none() {
+                sent.push(args);
+            }
+            taken.push((passed, result));
+        }
+
+        let mut resps = match sent.is_empty() {
+            true => Vec::new(),
+            false => send(sent, metadata)?,
+        }
+        .into_iter();
+        Ok(taken
+            .into_iter()
+            .map(|(passed, result)| {
+                let mut result = result.or_else(|| resps.next()).unwrap_or_else(|| {
+                    Err(Error::Internal(
+                        "no response to a request of the batch".into(),
+                    ))
+                });
+                self.respond(passed, &mut result);
+                result
+            })
+            .collect())
+    }
+
+    /// Pass a notification through the chain to `send`, it's dropped if an interceptor answers it
+    pub fn notify(
+        &self,
+        mut args: T,
+        mut metadata: Metadata,
+        send: impl FnOnce(T, Metadata) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        match self.request(&mut args, &mut metadata).1 {
+            None => send(args, metadata),
+            Some(result) => result.map(drop),
+        }
+    }
+
+    /// Pass a request through the chain until an interceptor takes it up,
+    /// returns how many interceptors it passed and the outcome they gave it
+    fn request(&self, args: &mut T, metadata: &mut Metadata) -> (usize, Option<Result<R, Error>>) {
+        for (i, interceptor) in self.interceptors.iter().enumerate() {
+            match interceptor.on_request(args, metadata) {
+                Intercept::Continue => continue,
+                Intercept::Respond(resp) => return (i, Some(Ok(resp))),
+                Intercept::Reject(reason) => return (i, Some(Err(Error::Rejected(reason)))),
+            }
+        }
+        (self.interceptors.len(), None)
+    }
+
+    /// Pass the outcome of a request back through the first `passed` interceptors
+    fn respond(&self, passed: usize, result: &mut Result<R, Error>) {
+        for interceptor in self.interceptors[..passed].iter().rev() {
+            interceptor.on_response(result);
+        }
+    }
+}
+
+impl<T, R> Default for ClientChain<T, R> {
+    fn default() -> Self {
+        Self {
+            interceptors: Vec::new(),
+        }
+    }
+}
+
+impl<T, R> Clone for ClientChain<T, R> {
+    fn clone(&self) -> Self {
+        Self {
+            interceptors: self.interceptors.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        sync::Arc,
+        vec,
+        vec::Vec,
+    };
+
+    use spin::Mutex;
+
+    use super::{ClientChain, ClientInterceptor, Intercept, ServerChain, ServerInterceptor};
+    use crate::{
+        context::{Metadata, RequestContext},
+        error::Error,
+    };
+
+    /// Logs what passes it, on a server it adds 10 to the args, rejects args of 10 and answers
+    /// args of 21 itself, on a client it rejects args of 0 and answers args of 3 itself
+    struct Logger {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Logger {
+        fn push(&self, event: &str) {
+            self.log.lock().push(format!("{} {event}", self.name));
+        }
+    }
+
+    impl ServerInterceptor<u64, u64> for Logger {
+        fn on_request(&self, _ctx: &RequestContext, args: &mut u64) -> Intercept<u64> {
+            self.push("request");
+            *args += 10;
+            match *args {
+                10 => Intercept::Reject("zero".to_string()),
+                21 => Intercept::Respond(1),
+                _ => Intercept::Continue,
+            }
+        }
+
+        fn on_response(&self, _ctx: &RequestContext, resp: &mut u64) {
+            self.push("response");
+            *resp *= 2;
+        }
+    }
+
+    impl ClientInterceptor<u64, u64> for Logger {
+        fn on_request(&self, args: &mut u64, metadata: &mut Metadata) -> Intercept<u64> {
+            self.push("request");
+            metadata.insert(self.name.to_string(), String::new());
+            match *args {
+                0 => Intercept::Reject("zero".to_string()),
+                3 => Intercept::Respond(30),
+                _ => Intercept::Continue,
+            }
+        }
+
+        fn on_response(&self, result: &mut Result<u64, Error>) {
+            self.push("response");
+            if let Ok(resp) = result {
+                *resp += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn chain_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = ServerChain::<u64, u64>::default();
+        for name in ["a", "b"] {
+            chain.push(Arc::new(Logger {
+                name,
+                log: log.clone(),
+            }));
+        }
+        let ctx = RequestContext::default();
+
+        // both interceptors add 10 on the way in and double on the way out
+        assert_eq!(chain.call(&ctx, 5, Ok), Ok(100));
+        assert_eq!(
+            *log.lock(),
+            ["a request", "b request", "b response", "a response"]
+        );
+
+        // the second one answers a one, and only the first one sees the response
+        log.lock().clear();
+        assert_eq!(chain.call(&ctx, 1, |_| unreachable!()), Ok(2));
+        assert_eq!(*log.lock(), ["a request", "b request", "a response"]);
+
+        // the first one rejects a zero before anyone else sees it
+        log.lock().clear();
+        assert!(chain.call(&ctx, 0, |_| unreachable!()).is_err());
+        assert_eq!(*log.lock(), ["a request"]);
+
+        // client interceptors may add metadata
+        let mut chain = ClientChain::<u64, u64>::default();
+        chain.push(Arc::new(Logger {
+            name: "c",
+            log: log.clone(),
+        }));
+        let resp = chain.call(1, Metadata::new(), |args, metadata| {
+            assert!(metadata.contains_key("c"));
+            Ok(args)
+        });
+        assert_eq!(resp.unwrap(), 2);
+
+        // the requests of a batch are taken up one by one, the rest are sent together
+        let resps = chain.call_batch(vec![1, 0, 3, 4], Metadata::new(), |args, metadata| {
+            assert!(metadata.contains_key("c"));
+            assert_eq!(args, [1, 4]);
+            Ok(args.into_iter().map(Ok).collect())
+        });
+        let resps = resps.unwrap();
+        assert!(matches!(
+            resps[..],
+            [Ok(2), Err(Error::Rejected(_)), Ok(30), Ok(5)]
+        ));
+
+        // a notification gets no response back
+        log.lock().clear();
+        chain.notify(1, Metadata::new(), |_, _| Ok(())).unwrap();
+        assert!(chain
+            .notify(0, Metadata::new(), |_, _| unreachable!())
+            .is_err());
+        chain
+            .notify(3, Metadata::new(), |_, _| unreachable!())
+            .unwrap();
+        assert_eq!(*log.lock(), ["c request", "c request", "c request"]);
+    }
+}
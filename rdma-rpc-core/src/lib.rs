@@ -1,15 +1,28 @@
-#![no_std]
-
 extern crate alloc;
 
 pub mod client_stub;
+pub mod codec;
+pub mod context;
+pub mod datagram;
+pub mod demux;
 pub mod error;
+pub mod interceptor;
 pub(crate) mod message_buffer;
 pub mod messages;
+#[cfg(feature = "prost")]
+pub mod protobuf;
+pub mod rc;
+pub mod recv_pool;
+pub mod rendezvous;
+pub mod retry;
 pub mod server_stub;
 pub mod session;
+pub mod slab;
 pub mod sliding_window;
+pub mod stream;
+pub mod streaming;
 pub mod transport;
 pub(crate) mod utils;
+pub mod wait;
 
 pub use sliding_window::SlidingWindow;
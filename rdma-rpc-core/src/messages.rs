@@ -1,58 +1,433 @@
-use alloc::vec::Vec;
-use core::{cmp::Ordering, fmt::Display};
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{cmp::Ordering, fmt::Display, ops::Deref};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
 use KRdmaKit::services_user::ibv_gid_wrapper;
 
+use crate::{
+    codec::{Codec, CodecKind},
+    context::Metadata,
+    error::Error,
+    slab::{OwnedSlot, Slot},
+    transport::RecvRing,
+};
+
+/// Size of a serialized packet without its data: `kind`, `ack_num`, `seq_num`, `session_id`,
+/// `stream_id` and the length of `data`, all fixed size in bincode
+pub(crate) const PACKET_HEADER_BYTES: usize = 1 + 8 + 8 + 8 + 4 + 8;
+
+/// Offset of `stream_id` in a serialized packet
+const STREAM_ID_OFFSET: usize = 25;
+
+/// Size of the crc32 following a message, on sessions that use checksums
+pub(crate) const CHECKSUM_BYTES: usize = 4;
+
+/// Kinds of packets
+pub(crate) mod kind {
+    /// bytes of the stream of a session
+    pub(crate) const DATA: u8 = 0;
+    /// acknowledges the data packet of `ack_num`
+    pub(crate) const ACK: u8 = 1;
+    /// a data packet carrying the descriptor of a message to be pulled with RDMA READ
+    pub(crate) const RENDEZVOUS: u8 = 2;
+    /// a standalone message that is never acked
+    pub(crate) const DATAGRAM: u8 = 3;
+    /// tells the remote end that a session is alive, carries nothing and is never acked
+    pub(crate) const KEEPALIVE: u8 = 4;
+}
+
 /// Packet is the base element transmitted on the rdma network
 #[derive(Serialize, Deserialize, Debug, Clone, Eq)]
-pub(crate) struct Packet {
-    is_ack: bool,
+pub struct Packet {
+    kind: u8,
     ack_num: u64,
     seq_num: u64,
     session_id: u64,
+    /// the stream of the session the packet belongs to, see [`crate::stream::StreamMux`]
+    stream_id: u32,
     data: Vec<u8>,
 }
 
 impl Packet {
     pub(crate) fn new_ack(ack_num: u64, session_id: u64) -> Packet {
         Packet {
-            is_ack: true,
+            kind: kind::ACK,
             ack_num,
             seq_num: 0,
             session_id,
+            stream_id: 0,
             data: Vec::new(),
         }
     }
 
+    pub(crate) fn new_keepalive(session_id: u64) -> Packet {
+        Packet {
+            kind: kind::KEEPALIVE,
+            ..Self::new_ack(0, session_id)
+        }
+    }
+
+    #[cfg(test)]
     pub(crate) fn new(seq_num: u64, session_id: u64, data: Vec<u8>) -> Packet {
         Self {
-            is_ack: false,
+            kind: kind::DATA,
             ack_num: 0,
             seq_num,
             session_id,
+            stream_id: 0,
             data,
         }
     }
 
+    pub(crate) fn set_stream_id(&mut self, stream_id: u32) {
+        self.stream_id = stream_id;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Write the header of a data packet of `kind` in front of its `data_len` bytes of data,
+    /// so that `buf` deserializes to the same packet as if the whole packet was serialized by bincode
+    ///
+    /// The packet is on stream 0, a stream port stamps its own id before posting.
+    pub(crate) fn write_header(
+        buf: &mut [u8],
+        kind: u8,
+        seq_num: u64,
+        session_id: u64,
+        data_len: usize,
+    ) {
+        buf[0] = kind;
+        buf[1..9].copy_from_slice(&0u64.to_le_bytes()); // ack_num
+        buf[9..17].copy_from_slice(&seq_num.to_le_bytes());
+        buf[17..STREAM_ID_OFFSET].copy_from_slice(&session_id.to_le_bytes());
+        Self::write_stream_id(buf, 0);
+        buf[STREAM_ID_OFFSET + 4..PACKET_HEADER_BYTES]
+            .copy_from_slice(&(data_len as u64).to_le_bytes());
+    }
+
+    fn write_stream_id(buf: &mut [u8], stream_id: u32) {
+        buf[STREAM_ID_OFFSET..STREAM_ID_OFFSET + 4].copy_from_slice(&stream_id.to_le_bytes());
+    }
+
+    /// Read the header of a packet written by bincode or `write_header`, returns it with the length of data
+    fn read_header(buf: &[u8]) -> Result<(Packet, usize), Error> {
+        if buf.len() < PACKET_HEADER_BYTES {
+            return Err(Error::Internal(format!("packet of {} bytes", buf.len())));
+        }
+        let read_u64 = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        let data_len = read_u64(STREAM_ID_OFFSET + 4) as usize;
+        if data_len > buf.len() - PACKET_HEADER_BYTES {
+            return Err(Error::Internal(format!(
+                "packet of {} bytes carries {data_len} bytes of data",
+                buf.len()
+            )));
+        }
+        let packet = Packet {
+            kind: buf[0],
+            ack_num: read_u64(1),
+            seq_num: read_u64(9),
+            session_id: read_u64(17),
+            stream_id: u32::from_le_bytes(
+                buf[STREAM_ID_OFFSET..STREAM_ID_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            ),
+            data: Vec::new(),
+        };
+        Ok((packet, data_len))
+    }
+}
+
+/// A data packet serialized in a send slot, it can be posted again for retransmission without copying
+pub struct PacketBuf {
+    slot: OwnedSlot,
+    seq_num: u64,
+    /// serialized size of the packet
+    len: u64,
+}
+
+impl PacketBuf {
+    /// Seal a slot whose first `data_len` bytes after `PACKET_HEADER_BYTES` are data
+    pub(crate) fn seal(
+        slot: OwnedSlot,
+        kind: u8,
+        seq_num: u64,
+        session_id: u64,
+        data_len: usize,
+    ) -> Self {
+        let buf = unsafe { slot.slot().as_mut_slice() };
+        Packet::write_header(buf, kind, seq_num, session_id, data_len);
+        Self {
+            slot,
+            seq_num,
+            len: (PACKET_HEADER_BYTES + data_len) as u64,
+        }
+    }
+
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq_num
+    }
+
+    /// Move the packet to another stream, the slot must not be posted
+    pub(crate) fn set_stream_id(&self, stream_id: u32) {
+        debug_assert!(!self.slot.is_posted());
+        Packet::write_stream_id(unsafe { self.slot.slot().as_mut_slice() }, stream_id);
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn slot(&self) -> &OwnedSlot {
+        &self.slot
+    }
+}
+
+/// A packet received in a recv buffer of a qp, its data is read in place
+///
+/// The buffer is posted to the qp again only when the packet is dropped, so holding many packets
+/// starves the qp of recv buffers. Call `detach` to copy the data out and give the buffer back early.
+pub struct RecvBuf {
+    /// the header of the packet, its data stays in the buffer
+    header: Packet,
+    data: RecvData,
+}
+
+enum RecvData {
+    Borrowed {
+        slot: LentSlot,
+        /// where the data starts in the slot
+        offset: usize,
+        len: usize,
+    },
+    Owned(Vec<u8>),
+}
+
+/// A recv slot lent out by a qp, reposted when dropped
+struct LentSlot {
+    slot: Slot,
+    ring: Arc<RecvRing>,
+}
+
+impl Drop for LentSlot {
+    fn drop(&mut self) {
+        if let Err(err) = self.ring.repost(&self.slot) {
+            warn!("failed to repost recv buffer, {err}");
+        }
+    }
+}
+
+impl RecvBuf {
+    /// Parse the packet of `len` bytes at `offset` of a recv slot
+    pub(crate) fn parse(
+        slot: Slot,
+        offset: usize,
+        len: usize,
+        ring: Arc<RecvRing>,
+    ) -> Result<Self, Error> {
+        let low = ring.is_low();
+        // the slot is reposted right away if the header is malformed
+        ring.lend();
+        let slot = LentSlot { slot, ring };
+        let bytes = unsafe { alloc::slice::from_raw_parts(slot.slot.as_ptr().add(offset), len) };
+        let (header, data_len) = Packet::read_header(bytes)?;
+        let mut packet = Self {
+            header,
+            data: RecvData::Borrowed {
+                slot,
+                offset: offset + PACKET_HEADER_BYTES,
+                len: data_len,
+            },
+        };
+        // leave the qp enough buffers to receive into, whoever holds the others
+        if low {
+            packet.detach();
+        }
+        Ok(packet)
+    }
+
     pub(crate) fn session_id(&self) -> u64 {
-        self.session_id
+        self.header.session_id
+    }
+
+    pub(crate) fn stream_id(&self) -> u32 {
+        self.header.stream_id
     }
 
     pub(crate) fn ack(&self) -> u64 {
-        self.ack_num
+        self.header.ack_num
     }
 
     pub(crate) fn seq(&self) -> u64 {
-        self.seq_num
+        self.header.seq_num
     }
 
     pub(crate) fn is_ack(&self) -> bool {
-        self.is_ack
+        self.header.kind == kind::ACK
     }
 
-    pub(crate) fn into_data(self) -> Vec<u8> {
-        self.data
+    pub(crate) fn is_rendezvous(&self) -> bool {
+        self.header.kind == kind::RENDEZVOUS
+    }
+
+    pub(crate) fn is_datagram(&self) -> bool {
+        self.header.kind == kind::DATAGRAM
+    }
+
+    pub(crate) fn is_keepalive(&self) -> bool {
+        self.header.kind == kind::KEEPALIVE
+    }
+
+    /// Replace the data with `data` pulled from the remote end, the recv buffer is reposted
+    pub(crate) fn set_data(&mut self, data: Vec<u8>) {
+        self.data = RecvData::Owned(data);
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match &self.data {
+            RecvData::Borrowed {
+                slot, offset, len, ..
+            } => unsafe { alloc::slice::from_raw_parts(slot.slot.as_ptr().add(*offset), *len) },
+            RecvData::Owned(data) => data,
+        }
+    }
+
+    /// Whether the data still lives in a recv buffer
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.data, RecvData::Borrowed { .. })
+    }
+
+    /// Copy the data out of the recv buffer and repost the buffer
+    pub fn detach(&mut self) {
+        if self.is_borrowed() {
+            let data = RecvData::Owned(self.data().to_vec());
+            self.data = data; // the lent slot is reposted when dropped here
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        match self.data {
+            RecvData::Owned(data) => data,
+            RecvData::Borrowed { .. } => self.data().to_vec(),
+        }
+    }
+}
+
+impl Deref for RecvBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data()
+    }
+}
+
+/// A whole message received by a session, as the list of packets it arrived in
+///
+/// The message is read straight from the recv buffers, which are reposted when it's dropped.
+pub struct Message {
+    bufs: Vec<RecvBuf>,
+    /// bytes of the first packet taken by the size prefix
+    skip: usize,
+    len: usize,
+}
+
+impl Message {
+    pub(crate) fn new(bufs: Vec<RecvBuf>, skip: usize, len: usize) -> Self {
+        Self { bufs, skip, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The data of the message as a scatter list of slices of the recv buffers
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        let mut left = self.len;
+        self.bufs.iter().enumerate().map(move |(i, buf)| {
+            let data = if i == 0 { &buf[self.skip..] } else { &buf[..] };
+            let data = &data[..data.len().min(left)];
+            left -= data.len();
+            data
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len);
+        for chunk in self.chunks() {
+            bytes.extend_from_slice(chunk);
+        }
+        bytes
+    }
+
+    /// Take off the checksum trailing the message, and check it against the data
+    pub(crate) fn strip_checksum(&mut self) -> Result<(), Error> {
+        let len = self.len.checked_sub(CHECKSUM_BYTES).ok_or_else(|| {
+            Error::Internal(format!("message of {} bytes has no checksum", self.len))
+        })?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut trailer = Vec::with_capacity(CHECKSUM_BYTES);
+        let mut offset = 0;
+        for chunk in self.chunks() {
+            let data = len.saturating_sub(offset).min(chunk.len());
+            hasher.update(&chunk[..data]);
+            trailer.extend_from_slice(&chunk[data..]);
+            offset += chunk.len();
+        }
+        self.len = len;
+
+        let expected = u32::from_be_bytes(trailer.try_into().unwrap());
+        let actual = hasher.finalize();
+        if actual != expected {
+            return Err(Error::Checksum(expected, actual));
+        }
+        Ok(())
+    }
+
+    /// Deserialize the message without gathering it into a contiguous buffer
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let mut chunks = self.chunks();
+        let reader = MessageReader {
+            current: chunks.next().unwrap_or_default(),
+            chunks,
+        };
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    /// Decode the message with `codec`, only bincode reads it without gathering it first
+    pub fn decode<T: DeserializeOwned>(&self, codec: CodecKind) -> Result<T, Error> {
+        match codec {
+            CodecKind::Bincode => self.deserialize(),
+            codec => codec.decode(&self.to_vec()),
+        }
+    }
+}
+
+/// Reads the chunks of a message one after another
+struct MessageReader<'a, I> {
+    current: &'a [u8],
+    chunks: I,
+}
+
+impl<'a, I: Iterator<Item = &'a [u8]>> std::io::Read for MessageReader<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.next() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = self.current.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current = &self.current[n..];
+        Ok(n)
     }
 }
 
@@ -74,12 +449,78 @@ impl PartialEq for Packet {
     }
 }
 
+/// A message sent by a client stub, it tells the server how the call goes on
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum Request<T> {
+    /// one request that is answered by one [`Reply`], it may be sent again on timeout
+    Unary(CallHeader, T),
+    /// requests answered together by their responses in order, each one may fail alone
+    Batch(CallHeader, Vec<T>),
+    /// a streaming call, frames of both ends follow the answer to it until it's closed
+    Stream(u64),
+    /// a request that is never answered
+    Notify(CallHeader, T),
+    /// the client gave up the unary call of this id, the server may stop working on it
+    Cancel(u64),
+}
+
+/// The answer to a request
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum Reply<R> {
+    Done(R),
+    /// the call wasn't handled, its deadline passed before the server got to it
+    Expired,
+    /// the call wasn't handled, or its response was dropped, since the client cancelled it
+    Cancelled,
+    /// an interceptor of the server turned the call down for this reason
+    Rejected(String),
+    /// the handler failed, e.g. it panicked
+    Failed(String),
+    /// the responses of a batch in order, each one may have failed alone
+    Batch(Vec<Result<R, String>>),
+    /// the streaming call is taken up, its frames follow
+    Stream,
+}
+
+/// A reply sent back with the id of the request it answers
+///
+/// A client skips the answers to copies of earlier calls by their ids, however many of the copies
+/// the server answered.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Answer<R> {
+    pub(crate) id: u64,
+    pub(crate) reply: Reply<R>,
+}
+
+/// The head of an [`Answer`], decoded alone to tell which request it answers without knowing its type
+#[derive(Deserialize)]
+pub(crate) struct AnswerHead {
+    pub(crate) id: u64,
+}
+
+/// Sent in front of a unary call, it identifies the call so that a retry is handled at most once
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CallHeader {
+    /// assigned by the client in increasing order, a retry keeps the id of the call
+    pub(crate) id: u64,
+    /// the client got the responses of all calls below this, the server may forget them
+    pub(crate) acked: u64,
+    /// how long the client still waits for the response, clocks of the two ends aren't in sync
+    pub(crate) timeout_micros: Option<u64>,
+    /// the call may run more than once, its response isn't kept for retries
+    pub(crate) idempotent: bool,
+    /// handed to the handler in its [`crate::context::RequestContext`]
+    pub(crate) metadata: Metadata,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QPInfo {
     pub lid: u32,
     pub gid: ibv_gid_wrapper,
     pub qp_num: u32,
     pub qkey: u32,
+    /// Active mtu of the port the qp is on
+    pub mtu: u64,
 }
 
 impl Display for QPInfo {
@@ -87,3 +528,36 @@ impl Display for QPInfo {
         write!(f, "{self:?}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::{kind, Packet, PACKET_HEADER_BYTES};
+
+    #[test]
+    fn packet_header_layout() {
+        let data = vec![1, 2, 3, 4];
+        let packet = Packet::new(7, 42, data.clone());
+
+        let mut buf = vec![0; PACKET_HEADER_BYTES + data.len()];
+        Packet::write_header(&mut buf, kind::DATA, 7, 42, data.len());
+        buf[PACKET_HEADER_BYTES..].copy_from_slice(&data);
+
+        assert_eq!(bincode::serialize(&packet).unwrap(), buf);
+        let decoded: Packet = bincode::deserialize(&buf).unwrap();
+        assert_eq!(decoded.session_id, 42);
+        assert_eq!(decoded.into_data(), data);
+
+        let (header, data_len) = Packet::read_header(&buf).unwrap();
+        assert_eq!((header.seq_num, header.session_id), (7, 42));
+        assert_eq!(data_len, data.len());
+
+        // the stream id is stamped in place
+        Packet::write_stream_id(&mut buf, 3);
+        let decoded: Packet = bincode::deserialize(&buf).unwrap();
+        assert_eq!(decoded.stream_id, 3);
+        assert_eq!(Packet::read_header(&buf).unwrap().0.stream_id, 3);
+        assert!(Packet::read_header(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! What the services generated by `rdma-rpc-build` from `.proto` files run on
+//!
+//! A service is served by one `ServerStub` whose args are [`ProtoCall`]s, the messages of requests
+//! and responses are encoded by prost and carried as bytes.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::{client_stub::ClientStub, error::Error};
+
+/// A call of a method of a protobuf service
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProtoCall {
+    /// `package.Service/Method`, as in the path of a gRPC call
+    pub method: String,
+    /// the request message
+    pub body: Vec<u8>,
+}
+
+/// The response message of a call, or why the service failed it
+pub type ProtoReply = Result<Vec<u8>, String>;
+
+/// Make a call of `method` and wait for its response
+pub fn call<Req: Message, Resp: Message + Default>(
+    client: &mut ClientStub,
+    method: &str,
+    request: &Req,
+) -> Result<Resp, Error> {
+    let call = ProtoCall {
+        method: method.to_string(),
+        body: request.encode_to_vec(),
+    };
+    let reply: ProtoReply = client.sync_call(call)?;
+    let body = reply.map_err(Error::Remote)?;
+    Resp::decode(body.as_slice()).map_err(|err| Error::DecodeEncode(err.to_string()))
+}
+
+/// Decode the request of a call, pass it to `handle` and encode the response
+pub fn dispatch<Req: Message + Default, Resp: Message>(
+    call: &ProtoCall,
+    handle: impl FnOnce(Req) -> Result<Resp, String>,
+) -> ProtoReply {
+    let request = Req::decode(call.body.as_slice())
+        .map_err(|err| format!("failed to decode the request of {}, {err}", call.method))?;
+    handle(request).map(|resp| resp.encode_to_vec())
+}
+
+/// The reply to a call of a method the service doesn't have
+pub fn unimplemented(call: &ProtoCall) -> ProtoReply {
+    Err(format!("no such method {}", call.method))
+}
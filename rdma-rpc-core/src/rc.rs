@@ -0,0 +1,350 @@
+use alloc::{format, string::ToString, sync::Arc, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+use spin::Mutex;
+use tracing::{debug, error, info};
+use KRdmaKit::{
+    context::Context, services_user::ibv_gid_wrapper, PreparedQueuePair, QueuePair,
+    QueuePairBuilder,
+};
+
+use crate::{
+    error::Error,
+    messages::{Packet, PacketBuf, RecvBuf, PACKET_HEADER_BYTES},
+    rendezvous::{RcChannel, ReadCompletions, READ_DEPTH},
+    slab::{OwnedSlot, SlabPool},
+    transport::{reap_send_cq, PacketTransport, QpConfig, RecvRing, ACK_SEND_SLOTS, POOL_SIZE},
+    utils::Backoff,
+    wait::{poll_until, CqEvents, WaitMode},
+};
+
+/// Size of the send and recv slots of an rc transport, the largest packet it carries
+///
+/// Unlike UD, an RC message isn't bounded by the mtu, the nic segments it.
+pub const RC_SLOT_BYTES: u64 = 32 * 1024;
+
+/// Times the nic retries a send the receiver has no recv buffer for, 7 means forever
+const RNR_RETRY: u8 = 7;
+/// How long the receiver asks the sender to wait before such a retry, 12 means 0.64ms
+const MIN_RNR_TIMER: u8 = 12;
+
+/// Address of an RC qp, exchanged before the qp is connected
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RcInfo {
+    pub lid: u32,
+    pub gid: ibv_gid_wrapper,
+    pub qp_num: u32,
+}
+
+/// An RC qp that is created but not connected yet
+pub struct PreparedRc {
+    context: Arc<Context>,
+    qp: PreparedQueuePair,
+    /// slots of the transport the qp may become
+    config: QpConfig,
+}
+
+impl PreparedRc {
+    pub fn new(context: Arc<Context>, port: u8) -> Result<Self, Error> {
+        Self::new_with_config(context, port, &QpConfig::default())
+    }
+
+    pub fn new_with_config(
+        context: Arc<Context>,
+        port: u8,
+        config: &QpConfig,
+    ) -> Result<Self, Error> {
+        let mut builder = QueuePairBuilder::new(&context);
+        builder
+            .enable_comp_channel()
+            .allow_remote_rw()
+            .set_port_num(port)
+            .set_gid_index(config.gid_index)
+            // reads of the rendezvous channel share the send queue with the sends
+            .set_max_send_wr((config.pool_size + READ_DEPTH) as _)
+            .set_max_recv_wr(config.pool_size as _)
+            .set_rnr_retry(RNR_RETRY)
+            .set_min_rnr_timer(MIN_RNR_TIMER);
+        let qp = builder
+            .build_rc()
+            .map_err(|err| Error::Internal(format!("failed to build rc, {err}")))?;
+        Ok(Self {
+            context,
+            qp,
+            config: *config,
+        })
+    }
+
+    /// The address to be sent to the remote end
+    pub fn info(&self) -> RcInfo {
+        RcInfo {
+            lid: self.qp.lid().unwrap(),
+            gid: ibv_gid_wrapper::from(self.qp.gid().unwrap()),
+            qp_num: self.qp.qp_num(),
+        }
+    }
+
+    fn bring_up(self, remote: RcInfo) -> Result<(Arc<Context>, Arc<QueuePair>, QpConfig), Error> {
+        let qp = self
+            .qp
+            .bring_up_rc(remote.lid, remote.gid.into(), remote.qp_num, 0)
+            .map_err(|err| Error::Internal(format!("failed to bring up rc, {err}")))?;
+        info!("rc qp {} connected to qp {}", qp.qp_num(), remote.qp_num);
+        Ok((self.context, qp, self.config))
+    }
+
+    /// Connect to the remote rc qp at `remote`, as the auxiliary rendezvous channel of a UD session
+    pub fn connect(self, remote: RcInfo) -> Result<RcChannel, Error> {
+        let (context, qp, _) = self.bring_up(remote)?;
+        Ok(RcChannel::new(context, qp))
+    }
+
+    /// Connect to the remote rc qp at `remote`, as the transport of a session
+    pub fn into_transport(self, remote: RcInfo) -> Result<RcTransport, Error> {
+        let (context, qp, config) = self.bring_up(remote)?;
+        RcTransport::new(qp, context, &config)
+    }
+}
+
+/// A transport over a connected RC qp
+///
+/// The nic retransmits lost packets and delivers them in order, so sessions on it don't ack
+/// packets. Packets keep the same framing as on UD, but can be as large as `RC_SLOT_BYTES`.
+///
+/// There is no flow control in software. A packet that arrives before the receiver has reposted
+/// a recv buffer is refused with an RNR NAK, and the nic resends it after `MIN_RNR_TIMER` for as
+/// long as it takes (`RNR_RETRY`), so a slow receiver stalls the sender instead of breaking the qp.
+pub struct RcTransport {
+    context: Arc<Context>,
+    qp: Arc<QueuePair>,
+    send_mrs: Arc<Mutex<SlabPool>>,
+    recv: Arc<RecvRing>,
+    /// reads of the rendezvous channel on `qp`, in case this transport reaps them
+    reads: Arc<ReadCompletions>,
+    events: Option<CqEvents>,
+    wait_mode: WaitMode,
+}
+
+impl RcTransport {
+    /// Create the slot pools of `config` and post all recv buffers to the qp
+    fn new(qp: Arc<QueuePair>, context: Arc<Context>, config: &QpConfig) -> Result<Self, Error> {
+        let send_mrs = Arc::new(Mutex::new(SlabPool::new(
+            Arc::clone(&context),
+            config.pool_size,
+            RC_SLOT_BYTES,
+            config.huge_pages,
+        )?));
+        let recv = RecvRing::new_exclusive(
+            Arc::clone(&qp),
+            Arc::clone(&context),
+            config.pool_size,
+            RC_SLOT_BYTES,
+            config.huge_pages,
+        )?;
+
+        Ok(Self {
+            context,
+            events: CqEvents::of_recv_cq(&qp),
+            qp,
+            send_mrs,
+            recv,
+            reads: Arc::new(Mutex::new(Vec::new())),
+            wait_mode: WaitMode::default(),
+        })
+    }
+
+    /// A rendezvous channel that reads over the qp of this transport, with no qp of its own
+    pub fn rendezvous_channel(&self) -> RcChannel {
+        RcChannel::new_shared(
+            Arc::clone(&self.context),
+            Arc::clone(&self.qp),
+            Arc::clone(&self.send_mrs),
+            Arc::clone(&self.reads),
+        )
+    }
+
+    fn post_slot(&self, slot: &OwnedSlot, size: u64) -> Result<(), Error> {
+        slot.mark_posted();
+        let slot = slot.slot();
+        self.qp
+            .post_send_send(slot.mr(), slot.range(size), true, slot.id)
+            .map_err(|err| {
+                error!("failed to post send: {err}");
+                Error::Internal(err.to_string())
+            })
+    }
+
+    /// Poll the recv cq once and return the packets received, possibly none
+    fn poll_recv(&self) -> Result<Vec<RecvBuf>, Error> {
+        let mut wcs = [Default::default(); POOL_SIZE as usize];
+        let res = self
+            .qp
+            .poll_recv_cq(&mut wcs)
+            .map_err(|err| Error::Internal(format!("failed to poll cq, {err}")))?;
+
+        let mut packets = Vec::new();
+        for wc in res {
+            // the qp is broken, e.g. the remote end is gone
+            if wc.status as u32 != 0 {
+                return Err(Error::Internal(format!(
+                    "rc recv failed, status: {}",
+                    wc.status as u32
+                )));
+            }
+            let slot = self.recv.get_slot(wc.wr_id)?;
+            packets.push(RecvBuf::parse(
+                slot,
+                0,
+                wc.byte_len as usize,
+                Arc::clone(&self.recv),
+            )?);
+        }
+
+        if !packets.is_empty() {
+            debug!("recv {} packets", packets.len());
+        }
+        Ok(packets)
+    }
+
+    pub fn set_wait_mode(&mut self, mode: WaitMode) {
+        self.wait_mode = mode;
+    }
+}
+
+impl PacketTransport for RcTransport {
+    fn send_burst(&mut self, packets: Vec<Packet>) -> Result<(), Error> {
+        for packet in packets.iter() {
+            // may take the slots kept for acks, they're freed once the send completes
+            let mut backoff = Backoff::new();
+            let slot = loop {
+                reap_send_cq(&self.qp, &self.send_mrs, Some(&self.reads))?;
+                if let Some(slot) = OwnedSlot::alloc(&self.send_mrs) {
+                    break slot;
+                }
+                backoff.snooze();
+            };
+            let size = bincode::serialized_size(packet)?;
+            assert!(size <= RC_SLOT_BYTES);
+            bincode::serialize_into(unsafe { slot.slot().as_mut_slice() }, packet)?;
+            self.post_slot(&slot, size)?;
+        }
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout_micros: Option<u64>) -> Result<Vec<RecvBuf>, Error> {
+        let events = self.events.clone();
+        let packets = poll_until(self.wait_mode, events.as_ref(), timeout_micros, || {
+            let packets = self.poll_recv()?;
+            Ok((!packets.is_empty()).then_some(packets))
+        })?;
+        Ok(packets.unwrap_or_default())
+    }
+
+    fn try_recv(&mut self) -> Result<Vec<RecvBuf>, Error> {
+        self.poll_recv()
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        RC_SLOT_BYTES as usize - PACKET_HEADER_BYTES
+    }
+
+    fn try_alloc_send_slot(&mut self) -> Result<Option<OwnedSlot>, Error> {
+        reap_send_cq(&self.qp, &self.send_mrs, Some(&self.reads))?;
+        Ok(OwnedSlot::alloc_leaving(&self.send_mrs, ACK_SEND_SLOTS))
+    }
+
+    fn post_bufs(&mut self, bufs: &[&PacketBuf]) -> Result<(), Error> {
+        reap_send_cq(&self.qp, &self.send_mrs, Some(&self.reads))?;
+        for buf in bufs {
+            if buf.slot().is_posted() {
+                continue;
+            }
+            debug!("post packet {}, size: {}", buf.seq(), buf.len());
+            self.post_slot(buf.slot(), buf.len())?;
+        }
+        Ok(())
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+
+    fn cq_events(&self) -> Option<CqEvents> {
+        self.events.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::{PreparedRc, RcTransport};
+    use crate::{
+        rendezvous::DEFAULT_RENDEZVOUS_THRESHOLD,
+        session::Session,
+        utils::tests::{new_random_data, new_test_context},
+    };
+
+    fn new_two_rc_transport() -> (RcTransport, RcTransport) {
+        let context = new_test_context();
+        let rc1 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let rc2 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let (info1, info2) = (rc1.info(), rc2.info());
+        (
+            rc1.into_transport(info2).unwrap(),
+            rc2.into_transport(info1).unwrap(),
+        )
+    }
+
+    #[test]
+    fn rc_session_works() {
+        let (tp1, tp2) = new_two_rc_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+
+        let huge_bytes = new_random_data(4 * 1024 * 1024); // 4MB
+        let huge_bytes_c = huge_bytes.clone();
+        let small_bytes = new_random_data(64);
+        let small_bytes_c = small_bytes.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(huge_bytes).unwrap();
+            assert_eq!(s1.recv::<Vec<u8>>().unwrap(), small_bytes_c);
+        });
+
+        let s2_handle = std::thread::spawn(move || {
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), huge_bytes_c);
+            s2.send(small_bytes).unwrap();
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+
+    #[test]
+    fn rc_session_rendezvous() {
+        let (tp1, tp2) = new_two_rc_transport();
+        // the channels read over the qps of the transports, sends and reads share the cqs
+        let (rc1, rc2) = (tp1.rendezvous_channel(), tp2.rendezvous_channel());
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+        s1.enable_rendezvous(rc1, DEFAULT_RENDEZVOUS_THRESHOLD);
+        s2.enable_rendezvous(rc2, DEFAULT_RENDEZVOUS_THRESHOLD);
+
+        let huge_bytes = new_random_data(4 * 1024 * 1024); // 4MB
+        let huge_bytes_c = huge_bytes.clone();
+        let small_bytes = new_random_data(64);
+        let small_bytes_c = small_bytes.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(huge_bytes).unwrap();
+            s1.send(small_bytes).unwrap();
+        });
+
+        let s2_handle = std::thread::spawn(move || {
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), huge_bytes_c);
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), small_bytes_c);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use alloc::{format, string::ToString, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use tracing::{debug, warn};
+use KRdmaKit::{context::Context, QueuePair};
+
+use crate::{
+    error::Error,
+    slab::{PoolStats, SlabPool, Slot},
+    transport::query_mtu,
+};
+
+/// Configuration of a [`SharedRecvPool`]
+#[derive(Debug, Clone, Copy)]
+pub struct SharedRecvConfig {
+    /// Total number of recv buffers shared by all qps
+    pub pool_size: usize,
+    /// How many buffers a qp is topped up to
+    pub posted_per_qp: usize,
+    /// A qp is topped up once the number of buffers posted to it drops below this
+    pub refill_threshold: usize,
+    /// Back the pool with huge pages if there are any
+    pub huge_pages: bool,
+}
+
+impl Default for SharedRecvConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 256,
+            posted_per_qp: 64,
+            refill_threshold: 16,
+            huge_pages: false,
+        }
+    }
+}
+
+/// One pool of recv buffers backing the recv queues of many qps
+///
+/// Instead of every qp pre-posting its own pool of buffers, qps borrow buffers from this pool.
+/// A consumed buffer goes back to the pool, and a qp is only refilled when it runs low,
+/// so the memory used for receiving is bounded by `pool_size` no matter how many qps there are.
+///
+/// `QueuePairBuilder` can't attach a verbs SRQ to a qp, so the sharing happens at the buffer level.
+pub struct SharedRecvPool {
+    config: SharedRecvConfig,
+    mrs: Mutex<SlabPool>,
+}
+
+impl SharedRecvPool {
+    /// Create a pool for the qps on `port`, every buffer is as large as the active mtu of the port
+    pub fn new(
+        context: Arc<Context>,
+        port: u8,
+        config: SharedRecvConfig,
+    ) -> Result<Arc<Self>, Error> {
+        if config.refill_threshold == 0
+            || config.refill_threshold > config.posted_per_qp
+            || config.posted_per_qp > config.pool_size
+        {
+            return Err(Error::Internal(format!(
+                "invalid shared recv config {config:?}, requires 0 < refill_threshold <= posted_per_qp <= pool_size"
+            )));
+        }
+
+        let mtu = query_mtu(&context, port)?;
+        let mrs = SlabPool::with_limit(
+            context,
+            config.pool_size,
+            config.pool_size,
+            mtu,
+            config.huge_pages,
+        )?;
+        Ok(Arc::new(Self {
+            config,
+            mrs: Mutex::new(mrs),
+        }))
+    }
+
+    pub fn config(&self) -> SharedRecvConfig {
+        self.config
+    }
+
+    pub(crate) fn slot_size(&self) -> u64 {
+        self.mrs.lock().slot_size()
+    }
+
+    /// Number of buffers that are not posted to any qp
+    pub fn free(&self) -> usize {
+        self.mrs.lock().free()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.mrs.lock().stats()
+    }
+
+    pub(crate) fn get_slot(&self, id: u64) -> Result<Slot, Error> {
+        self.mrs.lock().get_slot(id)
+    }
+
+    /// Give a consumed buffer back to the pool
+    pub(crate) fn release(&self, id: u64) -> Result<(), Error> {
+        self.mrs.lock().mark_slot_free(id)
+    }
+
+    /// If fewer than `refill_threshold` buffers are posted to `qp`, post free buffers until there are `posted_per_qp`
+    pub(crate) fn refill(&self, qp: &QueuePair, posted: &AtomicUsize) -> Result<(), Error> {
+        let current = posted.load(Ordering::Relaxed);
+        if current >= self.config.refill_threshold {
+            return Ok(());
+        }
+
+        let mut mrs = self.mrs.lock();
+        let mut n = 0;
+        for _ in current..self.config.posted_per_qp {
+            let slot = match mrs.get_free_slot() {
+                Some(slot) => slot,
+                None => {
+                    warn!(
+                        "shared recv pool exhausted, qp {} is refilled with {n} buffers",
+                        qp.qp_num()
+                    );
+                    break;
+                }
+            };
+            qp.post_recv(slot.mr(), slot.full_range(), slot.id)
+                .map_err(|err| Error::Internal(err.to_string()))?;
+            n += 1;
+        }
+        posted.fetch_add(n, Ordering::Relaxed);
+        debug!("refill qp {} with {n} recv buffers", qp.qp_num());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec};
+
+    use super::{SharedRecvConfig, SharedRecvPool};
+    use crate::{
+        demux::SharedTransport,
+        messages::Packet,
+        transport::{PacketTransport, Transport},
+        utils::tests::{new_random_data, new_test_context, new_test_qp},
+    };
+
+    #[test]
+    fn shared_recv_pool_refill() {
+        let context = new_test_context();
+        let config = SharedRecvConfig {
+            pool_size: 8,
+            posted_per_qp: 4,
+            refill_threshold: 2,
+            huge_pages: false,
+        };
+        let pool = SharedRecvPool::new(Arc::clone(&context), 1, config).unwrap();
+
+        // two server qps backed by the same pool
+        let shared1 =
+            SharedTransport::new_with_recv_pool(Arc::clone(&context), 1, Arc::clone(&pool))
+                .unwrap();
+        let shared2 =
+            SharedTransport::new_with_recv_pool(Arc::clone(&context), 1, Arc::clone(&pool))
+                .unwrap();
+        assert_eq!(pool.free(), 0);
+
+        let mut c1 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared1.qp_info(),
+            1,
+        )
+        .unwrap();
+        let mut c2 = Transport::new_with_qp(
+            new_test_qp(&context),
+            Arc::clone(&context),
+            shared2.qp_info(),
+            1,
+        )
+        .unwrap();
+        let mut s1 = shared1.open_session(1, c1.qp_info()).unwrap();
+        let mut s2 = shared2.open_session(2, c2.qp_info()).unwrap();
+
+        // far more packets than the pool size, buffers must be recycled between the two qps
+        for seq in 0..64 {
+            let data = new_random_data(64);
+            c1.send_burst(vec![Packet::new(seq, 1, data.clone())])
+                .unwrap();
+            assert_eq!(s1.recv().unwrap().remove(0).into_data(), data);
+
+            let data = new_random_data(64);
+            c2.send_burst(vec![Packet::new(seq, 2, data.clone())])
+                .unwrap();
+            assert_eq!(s2.recv().unwrap().remove(0).into_data(), data);
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let context = new_test_context();
+        let config = SharedRecvConfig {
+            pool_size: 8,
+            posted_per_qp: 16,
+            refill_threshold: 2,
+            huge_pages: false,
+        };
+        assert!(SharedRecvPool::new(context, 1, config).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use alloc::{collections::BTreeMap, format, sync::Arc, vec, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+use spin::Mutex;
+use tracing::debug;
+use KRdmaKit::{context::Context, MemoryRegion, QueuePair};
+
+use crate::{error::Error, slab::SlabPool, transport::reap_send_cq};
+
+/// Messages at least this large are pulled by the receiver with RDMA READ by default
+pub const DEFAULT_RENDEZVOUS_THRESHOLD: usize = 64 * 1024;
+const READ_CHUNK_BYTES: usize = 1024 * 1024; // bytes pulled by one RDMA READ
+pub(crate) const READ_DEPTH: usize = 16; // RDMA READs outstanding at a time
+const CHUNK_BITS: u32 = 24; // the wr_id of a read is the id of its pull, then the index of its chunk
+/// Set in the wr_id of every read, tells them apart from sends completing on the same cq
+pub(crate) const READ_WR_ID: u64 = 1 << 63;
+
+/// Completions of reads, `(wr_id, status)`, left for the channel by whoever polled them
+pub(crate) type ReadCompletions = Mutex<Vec<(u64, u32)>>;
+
+/// A buffer exposed to the remote end for RDMA READ, sent in a rendezvous packet
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct RemoteBuf {
+    pub(crate) addr: u64,
+    pub(crate) len: u64,
+    pub(crate) rkey: u32,
+}
+
+/// A message being pulled chunk by chunk
+struct Pull {
+    remote: RemoteBuf,
+    /// registered on `bytes`, must be dropped before `bytes` is handed out
+    mr: MemoryRegion,
+    bytes: Vec<u8>,
+    posted: usize,
+    completed: usize,
+}
+
+impl Pull {
+    fn chunks(&self) -> usize {
+        self.bytes.len().div_ceil(READ_CHUNK_BYTES)
+    }
+
+    fn is_done(&self) -> bool {
+        self.completed == self.chunks()
+    }
+}
+
+/// An RC qp a session pulls large messages through with one-sided RDMA READ
+///
+/// UD can't do one-sided verbs, so a session sends the descriptor of a large message as a
+/// rendezvous packet over UD, and the remote end reads the message through an auxiliary RC qp.
+/// A session over RC reads through the qp of its own transport instead, see
+/// [`RcTransport::rendezvous_channel`](crate::rc::RcTransport::rendezvous_channel).
+/// Reads don't block the caller, pulls are started and then polled along with the session.
+pub struct RcChannel {
+    context: Arc<Context>,
+    qp: Arc<QueuePair>,
+    /// send slots of the transport the qp is shared with, its sends complete on the same cq
+    send_mrs: Option<Arc<Mutex<SlabPool>>>,
+    reads: Arc<ReadCompletions>,
+    /// pulls that aren't finished yet, by id
+    pulls: BTreeMap<u64, Pull>,
+    /// reads posted but not completed, of all pulls
+    outstanding: usize,
+}
+
+impl RcChannel {
+    pub(crate) fn new(context: Arc<Context>, qp: Arc<QueuePair>) -> Self {
+        Self {
+            context,
+            qp,
+            send_mrs: None,
+            reads: Arc::new(Mutex::new(Vec::new())),
+            pulls: BTreeMap::new(),
+            outstanding: 0,
+        }
+    }
+
+    /// A channel on the qp of a transport, completions of either are reaped by both
+    pub(crate) fn new_shared(
+        context: Arc<Context>,
+        qp: Arc<QueuePair>,
+        send_mrs: Arc<Mutex<SlabPool>>,
+        reads: Arc<ReadCompletions>,
+    ) -> Self {
+        Self {
+            send_mrs: Some(send_mrs),
+            reads,
+            ..Self::new(context, qp)
+        }
+    }
+
+    /// Register `bytes` in place so the remote end can read them
+    pub(crate) fn expose(&self, mut bytes: Vec<u8>) -> Result<Exposed, Error> {
+        let mr =
+            MemoryRegion::new_from_raw(Arc::clone(&self.context), bytes.as_mut_ptr(), bytes.len())
+                .map_err(|err| Error::Internal(format!("failed to register message, {err}")))?;
+        Ok(Exposed { mr, bytes })
+    }
+
+    /// Start pulling the buffer described by `remote`, the pull is known by `id` until it's finished
+    ///
+    /// A buffer larger than `max_len` fails before anything is allocated for it.
+    pub(crate) fn start_pull(
+        &mut self,
+        id: u64,
+        remote: RemoteBuf,
+        max_len: usize,
+    ) -> Result<(), Error> {
+        if remote.len > max_len as u64 {
+            return Err(Error::TooLarge(remote.len as usize, max_len));
+        }
+        let mut bytes = vec![0; remote.len as usize];
+        let mr =
+            MemoryRegion::new_from_raw(Arc::clone(&self.context), bytes.as_mut_ptr(), bytes.len())
+                .map_err(|err| Error::Internal(format!("failed to register read buffer, {err}")))?;
+        self.pulls.insert(
+            id,
+            Pull {
+                remote,
+                mr,
+                bytes,
+                posted: 0,
+                completed: 0,
+            },
+        );
+        self.post_reads()
+    }
+
+    pub(crate) fn is_pulling(&self, id: u64) -> bool {
+        self.pulls.contains_key(&id)
+    }
+
+    pub(crate) fn has_pulls(&self) -> bool {
+        !self.pulls.is_empty()
+    }
+
+    /// Reap the completed reads and post more, returns the pulls finished by now with their bytes
+    pub(crate) fn poll_pulls(&mut self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        if self.pulls.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        match &self.send_mrs {
+            Some(send_mrs) => reap_send_cq(&self.qp, send_mrs, Some(&self.reads))?,
+            None => {
+                let mut wcs = [Default::default(); READ_DEPTH];
+                let res = self
+                    .qp
+                    .poll_send_cq(&mut wcs)
+                    .map_err(|err| Error::Internal(format!("failed to poll rc cq, {err}")))?;
+                let mut reads = self.reads.lock();
+                reads.extend(res.iter().map(|wc| (wc.wr_id, wc.status as u32)));
+            }
+        }
+        let completions = core::mem::take(&mut *self.reads.lock());
+        for (wr_id, status) in completions {
+            let wr_id = wr_id & !READ_WR_ID;
+            let (id, chunk) = (wr_id >> CHUNK_BITS, wr_id & ((1 << CHUNK_BITS) - 1));
+            if status != 0 {
+                return Err(Error::Internal(format!(
+                    "read of chunk {chunk} of pull {id} failed, status: {status}"
+                )));
+            }
+            if let Some(pull) = self.pulls.get_mut(&id) {
+                pull.completed += 1;
+            }
+            self.outstanding -= 1;
+        }
+        self.post_reads()?;
+
+        let done: Vec<u64> = self
+            .pulls
+            .iter()
+            .filter(|(_, pull)| pull.is_done())
+            .map(|(id, _)| *id)
+            .collect();
+        Ok(done
+            .into_iter()
+            .map(|id| {
+                let Pull { mr, bytes, .. } = self.pulls.remove(&id).unwrap();
+                drop(mr); // deregister before the buffer is handed out
+                debug!("pulled {} bytes from the remote end", bytes.len());
+                (id, bytes)
+            })
+            .collect())
+    }
+
+    /// Post the next chunks of the pulls in the order they started, up to `READ_DEPTH` at a time
+    fn post_reads(&mut self) -> Result<(), Error> {
+        for (id, pull) in self.pulls.iter_mut() {
+            while pull.posted < pull.chunks() {
+                if self.outstanding >= READ_DEPTH {
+                    return Ok(());
+                }
+                let start = pull.posted * READ_CHUNK_BYTES;
+                let end = (start + READ_CHUNK_BYTES).min(pull.bytes.len());
+                self.qp
+                    .post_send_read(
+                        &pull.mr,
+                        start as u64..end as u64,
+                        true,
+                        pull.remote.addr + start as u64,
+                        pull.remote.rkey,
+                        READ_WR_ID | (id << CHUNK_BITS) | pull.posted as u64,
+                    )
+                    .map_err(|err| Error::Internal(format!("failed to post read, {err}")))?;
+                pull.posted += 1;
+                self.outstanding += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A message registered for the remote end to read, deregistered when dropped
+pub(crate) struct Exposed {
+    mr: MemoryRegion,
+    /// the registered memory, must outlive `mr`
+    bytes: Vec<u8>,
+}
+
+impl Exposed {
+    pub(crate) fn remote_buf(&self) -> RemoteBuf {
+        RemoteBuf {
+            addr: self.mr.get_virt_addr() as u64,
+            len: self.bytes.len() as u64,
+            rkey: self.mr.rkey().0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use crate::{
+        error::Error,
+        rc::PreparedRc,
+        utils::tests::{new_random_data, new_test_context},
+    };
+
+    #[test]
+    fn rc_read() {
+        let context = new_test_context();
+        let rc1 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let rc2 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let (info1, info2) = (rc1.info(), rc2.info());
+        let (rc1, mut rc2) = (rc1.connect(info2).unwrap(), rc2.connect(info1).unwrap());
+
+        // larger than a chunk and not a multiple of it, and one that is pulled at the same time
+        let data1 = new_random_data(3 * 1024 * 1024 + 17);
+        let data2 = new_random_data(17);
+        let exposed1 = rc1.expose(data1.clone()).unwrap();
+        let exposed2 = rc1.expose(data2.clone()).unwrap();
+        rc2.start_pull(1, exposed1.remote_buf(), data1.len())
+            .unwrap();
+        rc2.start_pull(2, exposed2.remote_buf(), data1.len())
+            .unwrap();
+        assert!(rc2.is_pulling(1) && rc2.is_pulling(2));
+        let err = rc2.start_pull(3, exposed1.remote_buf(), 1024).unwrap_err();
+        assert!(matches!(err, Error::TooLarge(_, 1024)));
+        assert!(!rc2.is_pulling(3));
+
+        let mut pulled = Vec::new();
+        while rc2.has_pulls() {
+            pulled.extend(rc2.poll_pulls().unwrap());
+        }
+        pulled.sort_by_key(|(id, _)| *id);
+        assert_eq!(pulled, vec![(1, data1), (2, data2)]);
+    }
+}
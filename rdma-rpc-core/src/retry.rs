@@ -0,0 +1,111 @@
+use crate::{client_stub::DEFAULT_MAX_ATTEMPTS, utils::now_micros};
+
+/// How often and when a failed call is sent again
+///
+/// The wait before the n-th retry grows exponentially from `initial_backoff_micros` by `multiplier`,
+/// up to `max_backoff_micros`. Up to `jitter_percent` of every wait is random, so that clients
+/// failing together don't retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// how many times a call is sent at most, the first attempt included
+    pub max_attempts: usize,
+    pub initial_backoff_micros: u64,
+    pub max_backoff_micros: u64,
+    pub multiplier: u64,
+    pub jitter_percent: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff_micros: 1000,
+            max_backoff_micros: 100_000,
+            multiplier: 2,
+            jitter_percent: 50,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that sends every call once
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The wait before retry `retry`, counting from 1, `random` picks the jitter
+    pub fn backoff_micros(&self, retry: usize, random: u64) -> u64 {
+        let backoff = self.longest_backoff_micros(retry);
+        let jitter = backoff * self.jitter_percent.min(100) / 100;
+        backoff - jitter + random % (jitter + 1)
+    }
+
+    /// The longest a call may take if every attempt waits `timeout_micros` for the response
+    pub fn max_call_micros(&self, timeout_micros: u64) -> u64 {
+        let attempts = self.max_attempts.max(1);
+        (1..attempts).fold(
+            timeout_micros.saturating_mul(attempts as u64),
+            |total, retry| total.saturating_add(self.longest_backoff_micros(retry)),
+        )
+    }
+
+    /// The wait before retry `retry` without jitter, which only makes it shorter
+    fn longest_backoff_micros(&self, retry: usize) -> u64 {
+        let mut backoff = self.initial_backoff_micros;
+        for _ in 1..retry {
+            if backoff >= self.max_backoff_micros {
+                break;
+            }
+            backoff = backoff.saturating_mul(self.multiplier);
+        }
+        backoff.min(self.max_backoff_micros)
+    }
+}
+
+/// A xorshift generator for jitter, it only has to differ between clients
+pub(crate) struct Jitter(u64);
+
+impl Jitter {
+    pub(crate) fn new() -> Self {
+        Self(now_micros() | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Jitter, RetryPolicy};
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            jitter_percent: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff_micros(1, 0), 1000);
+        assert_eq!(policy.backoff_micros(2, 0), 2000);
+        assert_eq!(policy.backoff_micros(4, 0), 8000);
+        assert_eq!(policy.backoff_micros(100, 0), 100_000);
+
+        // the jitter takes at most half of the wait away
+        let policy = RetryPolicy::default();
+        let mut jitter = Jitter::new();
+        for _ in 0..100 {
+            let backoff = policy.backoff_micros(2, jitter.next());
+            assert!((1000..=2000).contains(&backoff));
+        }
+
+        // three attempts and the two waits between them at most
+        assert_eq!(policy.max_call_micros(10_000), 33_000);
+        assert_eq!(RetryPolicy::no_retry().max_call_micros(10_000), 10_000);
+    }
+}
@@ -1,51 +1,371 @@
 extern crate alloc;
 
-use alloc::sync::Arc;
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::mpsc,
+};
 
 use serde::{de::DeserializeOwned, Serialize};
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-use crate::session::Session;
+use crate::{
+    context::{PeerInfo, RequestContext},
+    error::Error,
+    interceptor::{ServerChain, ServerInterceptor},
+    messages::{Answer, CallHeader, Reply, Request},
+    session::Session,
+    streaming::StreamCall,
+};
 
 pub trait RpcHandler: Send + Sync {
     type Args;
     type Resp;
     fn handle(&self, arg: Self::Args) -> Self::Resp;
+
+    /// Handle a unary call knowing its session, peer, metadata and deadline,
+    /// and whether it's cancelled, by default the context is ignored
+    fn handle_with(&self, _ctx: &RequestContext, arg: Self::Args) -> Self::Resp {
+        self.handle(arg)
+    }
+
+    /// Handle a notification, which gets no response, by default the response of `handle` is dropped
+    fn handle_notify(&self, arg: Self::Args) {
+        self.handle(arg);
+    }
+
+    /// Handle a streaming call, receiving `Args` items and sending back `Resp` items
+    ///
+    /// The stream of responses is closed once this returns, an error is passed on to the client.
+    fn handle_stream(&self, _call: &mut StreamCall<'_, Self::Resp, Self::Args>) -> Result<(), Error>
+    where
+        Self::Resp: Serialize + Clone,
+        Self::Args: DeserializeOwned,
+    {
+        Err(Error::Internal(
+            "streaming calls are not supported".to_string(),
+        ))
+    }
 }
 
+/// How often the server looks for finished calls while waiting for requests, in micros
+const POLL_INTERVAL_MICROS: u64 = 100;
+
 pub struct ServerStub<T, R> {
     session: Session,
     handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+    /// threads the requests of a batch are spread over
+    batch_workers: usize,
+    /// the client of the session, handed to handlers
+    peer: Option<PeerInfo>,
+    interceptors: ServerChain<T, R>,
+}
+
+/// A request queued for the worker thread
+enum Work<T> {
+    Unary(CallHeader, RequestContext, T),
+    Batch(u64, RequestContext, Vec<T>),
+    Notify(RequestContext, T),
+}
+
+/// What the worker thread sends back for a finished request, with the id of a request to be answered
+enum Done<R> {
+    Unary(u64, Reply<R>),
+    Batch(u64, Reply<R>),
+    Notify,
 }
 
 impl<T, R> ServerStub<T, R>
 where
-    T: DeserializeOwned + Clone,
-    R: Serialize + Clone,
+    T: DeserializeOwned + Clone + Send + 'static,
+    R: Serialize + Clone + Send + 'static,
 {
     pub fn new(session: Session, handler: Arc<dyn RpcHandler<Args = T, Resp = R>>) -> Self {
-        Self { session, handler }
+        Self {
+            session,
+            handler,
+            batch_workers: 1,
+            peer: None,
+            interceptors: ServerChain::default(),
+        }
+    }
+
+    /// Handle the requests of a batch on up to `workers` threads, by default they are handled in turn
+    pub fn set_batch_workers(&mut self, workers: usize) {
+        self.batch_workers = workers.max(1);
+    }
+
+    /// Tell handlers who the client of the session is
+    pub fn set_peer(&mut self, peer: PeerInfo) {
+        self.peer = Some(peer);
+    }
+
+    /// Run `interceptor` around the handler, after the interceptors added before it
+    ///
+    /// Interceptors see every unary call, every request of a batch and every notification,
+    /// but not the items of a streaming call, which go straight to `handle_stream`.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn ServerInterceptor<T, R>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Run `interceptors` around the handler instead of the interceptors added so far
+    pub fn set_interceptors(&mut self, interceptors: ServerChain<T, R>) {
+        self.interceptors = interceptors;
     }
 
+    /// Serve requests in the order they arrive
+    ///
+    /// Requests are handled on a worker thread, so that this thread keeps receiving while a
+    /// handler runs and can flag a call as cancelled once the client gives it up.
     pub fn serve(mut self) -> ! {
+        let (work_tx, work_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut worker = Worker {
+            handler: Arc::clone(&self.handler),
+            interceptors: self.interceptors.clone(),
+            batch_workers: self.batch_workers,
+            replies: BTreeMap::new(),
+        };
+        std::thread::spawn(move || worker.run(work_rx, done_tx));
+
+        // requests queued or being handled, and the cancel flags of the unary calls among them
+        let mut inflight = 0usize;
+        let mut cancel_flags = CancelFlags::new();
         loop {
-            // validate the packet
-            let args = match self.session.recv() {
+            while let Ok(done) = done_rx.try_recv() {
+                inflight -= 1;
+                self.send_done(done, &mut cancel_flags);
+            }
+
+            // wait for requests as long as nothing is handled, otherwise look for finished ones now and then
+            let timeout = (inflight > 0).then_some(POLL_INTERVAL_MICROS);
+            let request = match self.session.recv_timeout::<Request<T>>(timeout) {
                 Err(err) => {
                     warn!("failed to recv new request, {err}");
                     continue;
                 }
-                Ok(packet) => packet,
+                Ok(None) => continue,
+                Ok(Some(request)) => request,
             };
             info!("new request from client");
 
-            // handle the request
-            let resp = self.handler.handle(args);
+            let work = match request {
+                Request::Unary(mut header, args) => {
+                    // the copies of a call share a flag, a cancel reaches whichever of them runs
+                    let (flag, copies) = cancel_flags.entry(header.id).or_default();
+                    *copies += 1;
+                    let ctx = self.context(&mut header).with_cancel_flag(Arc::clone(flag));
+                    Work::Unary(header, ctx, args)
+                }
+                Request::Batch(mut header, batch) => {
+                    Work::Batch(header.id, self.context(&mut header), batch)
+                }
+                Request::Notify(mut header, args) => Work::Notify(self.context(&mut header), args),
+                Request::Cancel(id) => {
+                    if let Some((flag, _)) = cancel_flags.get(&id) {
+                        debug!("call {id} is cancelled");
+                        flag.store(true, Ordering::Relaxed);
+                    }
+                    continue;
+                }
+                Request::Stream(id) => {
+                    // the stream takes over the session, the calls before it are answered first
+                    while inflight > 0 {
+                        let done = done_rx.recv().expect("the worker thread is gone");
+                        inflight -= 1;
+                        self.send_done(done, &mut cancel_flags);
+                    }
+                    if let Err(err) = self.serve_stream(id) {
+                        warn!("failed to serve stream call, {err}");
+                    }
+                    continue;
+                }
+            };
+            work_tx.send(work).expect("the worker thread is gone");
+            inflight += 1;
+        }
+    }
 
-            // send back the response
-            if let Err(e) = self.session.send(resp) {
-                warn!("failed to send response, {e}");
+    fn send_done(&mut self, done: Done<R>, cancel_flags: &mut CancelFlags) {
+        let sent = match done {
+            Done::Unary(id, reply) => {
+                if let Some((_, copies)) = cancel_flags.get_mut(&id) {
+                    *copies -= 1;
+                    if *copies == 0 {
+                        cancel_flags.remove(&id);
+                    }
+                }
+                self.session.send(Answer { id, reply })
             }
+            Done::Batch(id, reply) => self.session.send(Answer { id, reply }),
+            Done::Notify => return,
+        };
+        if let Err(e) = sent {
+            warn!("failed to send response, {e}");
         }
     }
+
+    /// The context of a request, it takes the metadata out of the header
+    fn context(&self, header: &mut CallHeader) -> RequestContext {
+        RequestContext::new(
+            self.session.id(),
+            self.peer,
+            mem::take(&mut header.metadata),
+            header.timeout_micros,
+        )
+    }
+
+    fn serve_stream(&mut self, id: u64) -> Result<(), Error> {
+        self.session.send(Answer::<R> {
+            id,
+            reply: Reply::Stream,
+        })?;
+        let mut call = StreamCall::new(&mut self.session);
+        match self.handler.handle_stream(&mut call) {
+            Ok(()) => call.close_send()?,
+            Err(err) => call.fail(err.to_string())?,
+        }
+        call.finish()
+    }
+}
+
+/// Call id to the flag its copies are cancelled with, and how many copies aren't answered yet
+type CancelFlags = BTreeMap<u64, (Arc<AtomicBool>, usize)>;
+
+/// Handles the requests of a server stub in the order they arrive
+struct Worker<T, R> {
+    handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+    interceptors: ServerChain<T, R>,
+    batch_workers: usize,
+    /// call id to the response sent for it, kept until the client acknowledges it
+    replies: BTreeMap<u64, R>,
+}
+
+impl<T: Send, R: Clone + Send> Worker<T, R> {
+    fn run(&mut self, work_rx: mpsc::Receiver<Work<T>>, done_tx: mpsc::Sender<Done<R>>) {
+        for work in work_rx {
+            let done = match work {
+                Work::Unary(header, ctx, args) => {
+                    Done::Unary(header.id, self.handle_once(header, &ctx, args))
+                }
+                Work::Batch(id, ctx, _) if ctx.is_expired() => {
+                    debug!("batch {id} expired while queued");
+                    Done::Batch(id, Reply::Expired)
+                }
+                Work::Batch(id, ctx, batch) => Done::Batch(
+                    id,
+                    Reply::Batch(handle_batch(
+                        &*self.handler,
+                        &self.interceptors,
+                        &ctx,
+                        batch,
+                        self.batch_workers,
+                    )),
+                ),
+                Work::Notify(ctx, _) if ctx.is_expired() => {
+                    debug!("notification expired while queued");
+                    Done::Notify
+                }
+                Work::Notify(ctx, args) => {
+                    self.interceptors
+                        .notify(&ctx, args, |args| self.handler.handle_notify(args));
+                    Done::Notify
+                }
+            };
+            if done_tx.send(done).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Handle a unary call unless it's a retry, whose response is taken from the reply cache,
+    /// or the client no longer waits for it
+    fn handle_once(&mut self, header: CallHeader, ctx: &RequestContext, args: T) -> Reply<R> {
+        // the client got the responses below `acked`, it never asks for them again
+        self.replies = self.replies.split_off(&header.acked);
+        if let Some(resp) = self.replies.get(&header.id) {
+            debug!("call {} is a retry, reply from the cache", header.id);
+            return Reply::Done(resp.clone());
+        }
+        if ctx.is_cancelled() {
+            return Reply::Cancelled;
+        }
+        if ctx.is_expired() {
+            debug!("call {} expired while queued", header.id);
+            return Reply::Expired;
+        }
+
+        let handler = &*self.handler;
+        let mut panicked = false;
+        let resp = match self.interceptors.call(ctx, args, |args| {
+            let resp = handle_caught(handler, ctx, args);
+            panicked = resp.is_err();
+            resp
+        }) {
+            Ok(resp) => resp,
+            Err(reason) if panicked => return Reply::Failed(reason),
+            Err(reason) => return Reply::Rejected(reason),
+        };
+        // the handler may have given up halfway, its response isn't worth keeping
+        if ctx.is_cancelled() {
+            return Reply::Cancelled;
+        }
+        // a retry of an idempotent call just runs it again
+        if !header.idempotent {
+            self.replies.insert(header.id, resp.clone());
+        }
+        Reply::Done(resp)
+    }
+}
+
+/// Run the handler on a request, a panic of the handler fails the request rather than the server
+fn handle_caught<T, R>(
+    handler: &dyn RpcHandler<Args = T, Resp = R>,
+    ctx: &RequestContext,
+    args: T,
+) -> Result<R, String> {
+    catch_unwind(AssertUnwindSafe(|| handler.handle_with(ctx, args)))
+        .map_err(|_| "handler panicked".to_string())
+}
+
+/// Handle the requests of a batch in order, or split into runs of consecutive requests
+/// on `workers` threads. A request whose handler panics, or that an interceptor rejects, fails alone.
+fn handle_batch<T: Send, R: Send>(
+    handler: &dyn RpcHandler<Args = T, Resp = R>,
+    interceptors: &ServerChain<T, R>,
+    ctx: &RequestContext,
+    batch: Vec<T>,
+    workers: usize,
+) -> Vec<Result<R, String>> {
+    let handle = |args| interceptors.call(ctx, args, |args| handle_caught(handler, ctx, args));
+
+    let workers = workers.min(batch.len());
+    if workers <= 1 {
+        return batch.into_iter().map(handle).collect();
+    }
+
+    let run_len = batch.len().div_ceil(workers);
+    let mut batch = batch.into_iter();
+    let runs: Vec<Vec<T>> = (0..workers)
+        .map(|_| batch.by_ref().take(run_len).collect())
+        .collect();
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = runs
+            .into_iter()
+            .map(|run| scope.spawn(move || run.into_iter().map(handle).collect::<Vec<_>>()))
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    })
 }
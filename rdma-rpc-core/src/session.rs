@@ -1,31 +1,155 @@
 use alloc::{
-    borrow::ToOwned,
-    collections::{BTreeMap, BTreeSet},
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::ToString,
     vec,
     vec::Vec,
 };
 
-use serde::{de::DeserializeOwned, Serialize};
-use tracing::debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use crate::{
+    codec::{Codec, CodecKind},
     error::Error,
-    messages::Packet,
-    transport::{Transport, MAX_DATA_BYTES},
-    utils::sleep_millis,
-    SlidingWindow,
+    messages::{kind, Message, Packet, PacketBuf, RecvBuf, CHECKSUM_BYTES, PACKET_HEADER_BYTES},
+    rendezvous::{RcChannel, RemoteBuf},
+    slab::OwnedSlot,
+    transport::{
+        max_data_bytes, PacketTransport, ACK_SEND_SLOTS, DEFAULT_POOL_SIZE, MAX_MTU, MIN_MTU,
+    },
+    utils::now_micros,
 };
 
-const MAX_POLL_CQ_RETRY: u32 = 100;
-const POLL_INTERVAL: u32 = 1;
-const WINDOW_SIZE: usize = 64;
+pub const DEFAULT_RETRANSMIT_TIMEOUT: u64 = 100_000; // micros without acks before the window is resent
+pub const DEFAULT_POLL_INTERVAL: u64 = 1000; // micros to wait for acks at most in one round
+pub const DEFAULT_WINDOW_SIZE: usize = DEFAULT_POOL_SIZE - ACK_SEND_SLOTS; // the slots acks don't need
+
+// a message keeps at most this many packets in recv buffers, and half as many may wait for reordering,
+// packets beyond these are copied out, and so are all packets while half of the buffers of a qp are held
+const HELD_RECV_BUFFERS: usize = 32;
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1 << 30; // unless both ends agree on a smaller limit
+
+/// The parameters both ends of a session agree on when it's set up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// packets a sender may have unacknowledged at a time
+    pub window_size: usize,
+    /// the largest mtu packets are sized for, the path mtu of the transport may be smaller
+    pub mtu: u64,
+    /// whether a crc32 of every message follows it, to catch corruption the nic doesn't
+    pub checksum: bool,
+    /// the largest message sent or received, a larger one fails
+    pub max_message_bytes: usize,
+    /// micros between the keepalives an end waiting for packets sends, `None` to send none
+    pub keepalive_micros: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            window_size: DEFAULT_WINDOW_SIZE,
+            mtu: MAX_MTU,
+            checksum: false,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            keepalive_micros: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.window_size == 0 {
+            return Err(Error::Internal(
+                "window size must be at least 1".to_string(),
+            ));
+        }
+        if !(MIN_MTU..=MAX_MTU).contains(&self.mtu) {
+            return Err(Error::Internal(format!(
+                "mtu {} is out of {MIN_MTU}..={MAX_MTU}",
+                self.mtu
+            )));
+        }
+        if self.keepalive_micros == Some(0) {
+            return Err(Error::Internal(
+                "keepalive interval must be at least 1 micro".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Agree on the config `proposed` by the remote end within the limits of this one
+    ///
+    /// Limits take the smaller one of both ends, and checksums and keepalives are used if either end
+    /// asks for them, at the shorter interval if both do.
+    pub fn agree(&self, proposed: &SessionConfig) -> Result<SessionConfig, Error> {
+        proposed.validate()?;
+        Ok(SessionConfig {
+            window_size: self.window_size.min(proposed.window_size),
+            mtu: self.mtu.min(proposed.mtu),
+            checksum: self.checksum || proposed.checksum,
+            max_message_bytes: self.max_message_bytes.min(proposed.max_message_bytes),
+            keepalive_micros: match (self.keepalive_micros, proposed.keepalive_micros) {
+                (Some(ours), Some(theirs)) => Some(ours.min(theirs)),
+                (ours, theirs) => ours.or(theirs),
+            },
+        })
+    }
+
+    /// Check the config the remote end `agreed` on for a proposal of this one,
+    /// it may lower the limits but never raise them, drop checksums or send keepalives less often
+    pub fn check_agreed(&self, agreed: &SessionConfig) -> Result<(), Error> {
+        agreed.validate()?;
+        let raised = [
+            (
+                "window size",
+                agreed.window_size as u64,
+                self.window_size as u64,
+            ),
+            ("mtu", agreed.mtu, self.mtu),
+            (
+                "max message bytes",
+                agreed.max_message_bytes as u64,
+                self.max_message_bytes as u64,
+            ),
+        ];
+        if let Some((name, larger, proposed)) = raised
+            .into_iter()
+            .find(|(_, agreed, proposed)| agreed > proposed)
+        {
+            return Err(Error::Internal(format!(
+                "agreed {name} {larger} is larger than the proposed {proposed}"
+            )));
+        }
+        if self.checksum && !agreed.checksum {
+            return Err(Error::Internal(
+                "checksums were proposed but not agreed on".to_string(),
+            ));
+        }
+        match (self.keepalive_micros, agreed.keepalive_micros) {
+            (Some(proposed), None) => {
+                return Err(Error::Internal(format!(
+                    "keepalives every {proposed} micros were proposed but not agreed on"
+                )))
+            }
+            (Some(proposed), Some(agreed)) if agreed > proposed => {
+                return Err(Error::Internal(format!(
+                    "agreed keepalive interval {agreed} is longer than the proposed {proposed}"
+                )))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
 /// Session provides send/receive between server/client
 /// Session should act like a stream. Users will read/write from this object by using `send_bytes` and `recv_bytes`.
 /// User can also pass in a serializable structure to send, or deserializable structure to recv.
-/// Session will handle reorder and package loss.
+/// Session will handle reorder and package loss, unless the transport is reliable.
 pub struct Session {
-    transport: Transport,
+    transport: Box<dyn PacketTransport>,
     /// Session ID
     id: u64,
     /// the largest seq of all packets sent
@@ -33,86 +157,144 @@ pub struct Session {
     /// the largest ack of all packets received and acknowledged
     ack: u64,
     /// seq to packet
-    recv_buffer: BTreeMap<u64, Packet>,
+    recv_buffer: BTreeMap<u64, RecvBuf>,
+    /// packets of the message being received, taken from `recv_buffer` in order
+    partial: Vec<RecvBuf>,
+    /// bytes in `partial`
+    partial_len: usize,
+    /// messages at least `threshold` bytes are pulled by the remote end over the channel
+    rendezvous: Option<(RcChannel, usize)>,
+    /// agreed on with the remote end, both ends must use the same one
+    config: SessionConfig,
+    /// encodes the values sent and received, both ends must use the same one
+    codec: CodecKind,
+    /// micros without acks before the window is resent
+    retransmit_timeout: u64,
+    /// micros to wait for acks at most in one round
+    poll_interval: u64,
+    /// when a packet of the remote end last arrived
+    last_heard: u64,
+    /// when a keepalive was last sent
+    last_keepalive: u64,
 }
 
 impl Session {
     // TODO: exchange ack and syn using tcp
-    pub fn new(id: u64, transport: Transport) -> Self {
+    pub fn new(id: u64, transport: impl PacketTransport + 'static) -> Self {
+        Self::with_config(id, transport, SessionConfig::default())
+    }
+
+    /// Create a session that runs with `config`, which must be valid
+    pub fn with_config(
+        id: u64,
+        transport: impl PacketTransport + 'static,
+        config: SessionConfig,
+    ) -> Self {
+        debug_assert!(config.validate().is_ok());
         Self {
-            transport,
+            transport: Box::new(transport),
             id,
             seq: 0,
             ack: 0,
             recv_buffer: BTreeMap::new(),
+            partial: Vec::new(),
+            partial_len: 0,
+            rendezvous: None,
+            config,
+            codec: CodecKind::default(),
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            last_heard: now_micros(),
+            last_keepalive: 0,
         }
     }
 
+    /// Send messages of at least `threshold` bytes by rendezvous over `channel`
+    ///
+    /// Only a descriptor of such a message is sent, the remote end pulls the message with RDMA READ.
+    /// The remote end must enable rendezvous as well, with a channel connected to `channel`.
+    pub fn enable_rendezvous(&mut self, channel: RcChannel, threshold: usize) {
+        self.rendezvous = Some((channel, threshold));
+    }
+
+    fn use_rendezvous(&self, len: usize) -> bool {
+        matches!(&self.rendezvous, Some((_, threshold)) if len >= *threshold)
+    }
+
     pub fn id(&self) -> u64 {
         self.id
     }
 
-    // will ensure all bytes are sent and acknowledged by the remote end
-    pub fn send_bytes(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
-        debug!("sending {} bytes", bytes.len());
+    /// Encode values with `codec` instead of bincode, the remote end must switch to the same codec
+    pub fn set_codec(&mut self, codec: CodecKind) {
+        self.codec = codec;
+    }
 
-        let packets = self.make_packets(bytes); // all packets to be sent
-        let mut waiting: BTreeSet<u64> = packets.iter().map(|packet| packet.seq()).collect(); // packets that are waiting to be acknowledged by the remote end
-        let mut window = SlidingWindow::new(packets.as_slice(), WINDOW_SIZE); // current window
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
 
-        loop {
-            let packets: Vec<_> = window
-                .get()
-                .iter()
-                .filter_map(|packet| waiting.contains(&packet.seq()).then(|| packet.to_owned()))
-                .collect();
-            assert!(!packets.is_empty());
-
-            self.transport.send_burst(packets)?;
-
-            let mut round = 0;
-            while round < MAX_POLL_CQ_RETRY {
-                sleep_millis(POLL_INTERVAL);
-
-                // recv acks, if reieved packets are not ack, insert them to recv_buffer and send back acks
-                let packets = self.transport.try_recv()?;
-                let mut acks = vec![];
-                for packet in packets {
-                    if !packet.is_ack() {
-                        acks.push(Packet::new_ack(packet.seq(), self.id));
-                        self.insert_recv_buffer(packet);
-                    } else {
-                        waiting.remove(&packet.ack());
-                    }
-                }
-                self.transport.send_burst(acks)?;
-
-                // try to move the window
-                let last_sent_seq = window.last().seq();
-                while !waiting.contains(&window.first().seq()) {
-                    window.slide();
-                    if window.is_closed() {
-                        return Ok(());
-                    }
-                }
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
 
-                // if window has moved, send new packets to the remote end and reset waiting round
-                if window.last().seq() > last_sent_seq {
-                    round = 0; // reset round
-
-                    let new_packets = window
-                        .get()
-                        .iter()
-                        .filter_map(|packet| {
-                            (packet.seq() > last_sent_seq).then(|| packet.to_owned())
-                        })
-                        .collect();
-                    self.transport.send_burst(new_packets)?;
-                } else {
-                    round += 1;
-                }
-            }
+    /// Resend the unacknowledged packets after `timeout_micros` without acks
+    pub fn set_retransmit_timeout(&mut self, timeout_micros: u64) {
+        self.retransmit_timeout = timeout_micros;
+    }
+
+    /// Wait for acks for at most `interval_micros` in one round, before checking for retransmission
+    pub fn set_poll_interval(&mut self, interval_micros: u64) {
+        self.poll_interval = interval_micros;
+    }
+
+    /// Micros since a packet of the remote end last arrived
+    ///
+    /// With keepalives agreed on, a remote end that waits for packets is heard from about once
+    /// an interval, a much longer silence means it's gone.
+    pub fn silent_micros(&self) -> u64 {
+        now_micros().saturating_sub(self.last_heard)
+    }
+
+    /// Send a keepalive if one is due, the caller is waiting for packets
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        let Some(interval) = self.config.keepalive_micros else {
+            return Ok(());
+        };
+        let now = now_micros();
+        if now - self.last_keepalive >= interval {
+            self.last_keepalive = now;
+            self.transport
+                .send_burst(vec![Packet::new_keepalive(self.id)])?;
         }
+        Ok(())
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.config.max_message_bytes {
+            return Err(Error::TooLarge(size, self.config.max_message_bytes));
+        }
+        Ok(())
+    }
+
+    /// Append the checksum of a message to `data`, the message follows the size prefix
+    fn append_checksum(&self, data: &mut Vec<u8>) {
+        if self.config.checksum {
+            let checksum = crc32fast::hash(&data[8..]);
+            data.extend_from_slice(&checksum.to_be_bytes());
+        }
+    }
+
+    // will ensure all bytes are sent and acknowledged by the remote end
+    pub fn send_bytes(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        debug!("sending {} bytes", bytes.len());
+        if self.use_rendezvous(bytes.len()) {
+            return self.send_rendezvous(bytes);
+        }
+
+        let mut outgoing = Outgoing::new(self);
+        outgoing.write_bytes(&bytes)?;
+        outgoing.finish()
     }
 
     // will return as soon as some bytes are received(order is guaranteed)
@@ -120,94 +302,503 @@ impl Session {
         loop {
             // check if there are bytes ready to be returned to users
             let mut ready_bytes = vec![];
-            while let Some(packet) = self.recv_buffer.remove(&self.ack) {
-                self.ack += 1;
-                let mut data = packet.into_data();
-                ready_bytes.append(&mut data);
+            while let Some(packet) = self.next_in_order() {
+                ready_bytes.extend_from_slice(&packet);
             }
             if !ready_bytes.is_empty() {
                 debug!("received {} bytes", ready_bytes.len());
                 return Ok(ready_bytes);
             }
 
-            let packets = self.transport.recv()?;
+            self.poll_packets(None)?;
+        }
+    }
 
-            // send back acks
-            let mut acks = vec![];
-            for packet in packets {
-                assert_eq!(self.id(), packet.session_id());
-                if packet.is_ack() {
-                    continue;
-                }
+    /// Receive a whole message sent by `send`, without copying it out of the recv buffers
+    ///
+    /// The recv buffers are reposted only after the message is dropped. A message spanning more than
+    /// `HELD_RECV_BUFFERS` packets has its tail copied out, since a qp only has so many recv buffers.
+    pub fn recv_message(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Some(message) = self.recv_message_timeout(None)? {
+                return Ok(message);
+            }
+        }
+    }
 
-                // insert the packet to buffer and reply with ack
-                let ack_num = packet.seq();
-                acks.push(Packet::new_ack(packet.seq(), self.id));
-                debug!("send ack {ack_num}");
+    /// Like `recv_message`, but give up after `timeout_micros`, returns `None` on timeout
+    ///
+    /// The part of a message received before the timeout is kept for the next receive.
+    pub fn recv_message_timeout(
+        &mut self,
+        timeout_micros: Option<u64>,
+    ) -> Result<Option<Message>, Error> {
+        let deadline = timeout_micros.map(|timeout| now_micros() + timeout);
+        loop {
+            while let Some(mut packet) = self.next_in_order() {
+                self.partial_len += packet.len();
+                if self.partial.len() >= HELD_RECV_BUFFERS {
+                    packet.detach();
+                }
+                self.partial.push(packet);
 
-                self.insert_recv_buffer(packet);
+                // a message always starts at a new packet, so its size prefix is in the first packet
+                let prefix = self.partial[0].get(0..8).ok_or_else(|| {
+                    Error::Internal(format!(
+                        "message starts with {} bytes",
+                        self.partial[0].len()
+                    ))
+                })?;
+                let size = usize::from_be_bytes(prefix.try_into().unwrap());
+                if self.partial.len() == 1 {
+                    debug!("need to recv {size} bytes");
+                    if let Err(err) = self.check_size(size) {
+                        // the remote end broke the agreed config, the session can't recover
+                        self.partial.clear();
+                        self.partial_len = 0;
+                        return Err(err);
+                    }
+                }
+                let trailer = if self.config.checksum {
+                    CHECKSUM_BYTES
+                } else {
+                    0
+                };
+                if self.partial_len >= 8 + size + trailer {
+                    self.partial_len = 0;
+                    let bufs = core::mem::take(&mut self.partial);
+                    let mut message = Message::new(bufs, 8, size + trailer);
+                    if self.config.checksum {
+                        message.strip_checksum()?;
+                    }
+                    return Ok(Some(message));
+                }
             }
-            self.transport.send_burst(acks)?;
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = now_micros();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            self.poll_packets(timeout)?;
         }
     }
 
     pub fn send<T: Serialize + Clone>(&mut self, value: T) -> Result<(), Error> {
         debug!("start sending");
 
-        // prepare data
+        // only bincode serializes straight into the send slots
+        if self.codec != CodecKind::Bincode {
+            let encoded = self.codec.encode(&value)?;
+            self.check_size(encoded.len())?;
+            let mut data = Vec::with_capacity(8 + encoded.len() + CHECKSUM_BYTES);
+            data.extend_from_slice(&encoded.len().to_be_bytes());
+            data.extend_from_slice(&encoded);
+            self.append_checksum(&mut data);
+            return self.send_bytes(data);
+        }
+
         let size = bincode::serialized_size(&value)? as usize;
-        let mut data = vec![0; 8 + size];
-        data[0..8].copy_from_slice(&size.to_be_bytes());
-        bincode::serialize_into(&mut data[8..], &value)?;
+        self.check_size(size)?;
+        if self.use_rendezvous(8 + size) {
+            let mut data = vec![0; 8 + size];
+            data[0..8].copy_from_slice(&size.to_be_bytes());
+            bincode::serialize_into(&mut data[8..], &value)?;
+            self.append_checksum(&mut data);
+            return self.send_rendezvous(data);
+        }
 
-        self.send_bytes(data)?;
+        // serialize straight into the send slots
+        let checksum = self.config.checksum;
+        let mut outgoing = Outgoing::new(self);
+        outgoing.write_bytes(&size.to_be_bytes())?;
+        if checksum {
+            outgoing.checksum = Some(crc32fast::Hasher::new());
+        }
+        if let Err(err) = bincode::serialize_into(&mut outgoing, &value) {
+            return Err(outgoing.error.take().unwrap_or_else(|| err.into()));
+        }
+        outgoing.write_checksum()?;
+        outgoing.finish()?;
 
         debug!("send succeeded");
         Ok(())
     }
 
+    /// Expose `bytes` and send their descriptor, returns after the remote end pulled them
+    ///
+    /// The remote end acknowledges the descriptor only after its RDMA READ completed.
+    fn send_rendezvous(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        let (channel, _) = self.rendezvous.as_ref().unwrap();
+        let exposed = channel.expose(bytes)?;
+        debug!("send {} bytes by rendezvous", exposed.remote_buf().len);
+
+        let mut outgoing = Outgoing::new(self);
+        outgoing.write_bytes(&bincode::serialize(&exposed.remote_buf())?)?;
+        outgoing.seal_as(kind::RENDEZVOUS)?;
+        outgoing.finish()?;
+
+        drop(exposed);
+        Ok(())
+    }
+
     pub fn recv<R: DeserializeOwned>(&mut self) -> Result<R, Error> {
         debug!("start receiving");
 
-        let mut bytes = self.recv_bytes()?;
-        let size = usize::from_be_bytes(bytes[0..8].try_into().unwrap());
-        debug!("need to recv {} bytes", size);
-        bytes.reserve(size);
+        let value = self.recv_message()?.decode(self.codec)?;
+
+        debug!("receive suceeded");
+        Ok(value)
+    }
+
+    /// Like `recv`, but give up after `timeout_micros`, returns `None` on timeout
+    pub fn recv_timeout<R: DeserializeOwned>(
+        &mut self,
+        timeout_micros: Option<u64>,
+    ) -> Result<Option<R>, Error> {
+        match self.recv_message_timeout(timeout_micros)? {
+            Some(message) => Ok(Some(message.decode(self.codec)?)),
+            None => Ok(None),
+        }
+    }
 
-        while bytes.len() < size + 8 {
-            let mut new_bytes = self.recv_bytes()?;
-            bytes.append(&mut new_bytes);
+    /// Take the packet next in order if it's received, and the message of a rendezvous packet is pulled
+    fn next_in_order(&mut self) -> Option<RecvBuf> {
+        if self.is_pulling(self.ack) {
+            return None;
         }
+        let packet = self.recv_buffer.remove(&self.ack)?;
+        self.ack += 1;
+        Some(packet)
+    }
 
-        debug!("receive suceeded");
-        Ok(bincode::deserialize(&bytes[8..(8 + size)])?)
+    fn is_pulling(&self, seq: u64) -> bool {
+        matches!(&self.rendezvous, Some((channel, _)) if channel.is_pulling(seq))
+    }
+
+    /// Move the pulls of rendezvous messages on, and ack the rendezvous packets of those finished
+    fn poll_pulls(&mut self) -> Result<(), Error> {
+        let pulled = match self.rendezvous.as_mut() {
+            Some((channel, _)) => channel.poll_pulls()?,
+            None => return Ok(()),
+        };
+        let mut acks = vec![];
+        for (seq, bytes) in pulled {
+            if let Some(packet) = self.recv_buffer.get_mut(&seq) {
+                packet.set_data(bytes);
+            }
+            debug!("message of packet {seq} is pulled, send ack");
+            acks.push(Packet::new_ack(seq, self.id));
+        }
+        if !acks.is_empty() {
+            self.transport.send_burst(acks)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for packets for at most `timeout_micros`, buffer the data packets and reply with acks
+    fn poll_packets(&mut self, timeout_micros: Option<u64>) -> Result<(), Error> {
+        // don't wait long while messages are pulled, their reads complete without packets arriving
+        let pulling = matches!(&self.rendezvous, Some((channel, _)) if channel.has_pulls());
+        let timeout_micros = match timeout_micros {
+            Some(timeout) if pulling => Some(timeout.min(self.poll_interval)),
+            None if pulling => Some(self.poll_interval),
+            timeout => timeout,
+        };
+        // wake up in time for the next keepalive
+        let timeout_micros = match (timeout_micros, self.config.keepalive_micros) {
+            (Some(timeout), Some(interval)) => Some(timeout.min(interval)),
+            (None, interval) => interval,
+            (timeout, None) => timeout,
+        };
+        self.keep_alive()?;
+        let packets = self.transport.recv_timeout(timeout_micros)?;
+        if !packets.is_empty() {
+            self.last_heard = now_micros();
+        }
+
+        // send back acks
+        let mut acks = vec![];
+        for packet in packets {
+            assert_eq!(self.id(), packet.session_id());
+            if packet.is_ack() || packet.is_keepalive() {
+                continue;
+            }
+            // datagrams are out of the window, a datagram channel must have an id of its own
+            if packet.is_datagram() {
+                warn!("drop datagram {} sent to session {}", packet.seq(), self.id);
+                continue;
+            }
+
+            // insert the packet to buffer and reply with ack
+            if self.needs_ack(&packet) {
+                let ack_num = packet.seq();
+                acks.push(Packet::new_ack(packet.seq(), self.id));
+                debug!("send ack {ack_num}");
+            }
+
+            self.insert_recv_buffer(packet)?;
+        }
+        if !acks.is_empty() {
+            self.transport.send_burst(acks)?;
+        }
+        self.poll_pulls()
+    }
+
+    /// A reliable transport never loses packets, so data packets are only acked on an unreliable one
+    ///
+    /// A rendezvous packet is acked once its message is pulled, for the sender to learn that
+    /// it may release the message, and acked again if it's retransmitted after that.
+    fn needs_ack(&self, packet: &RecvBuf) -> bool {
+        if packet.is_rendezvous() {
+            let seq = packet.seq();
+            return seq < self.ack
+                || (self.recv_buffer.contains_key(&seq) && !self.is_pulling(seq));
+        }
+        !self.transport.is_reliable()
+    }
+
+    /// Buffer a data packet until it's consumed in order, the caller acks it after this returns
+    ///
+    /// The message of a rendezvous packet starts being pulled, the packet is consumed only after that.
+    fn insert_recv_buffer(&mut self, mut packet: RecvBuf) -> Result<(), Error> {
+        assert!(!packet.is_ack() && !packet.is_keepalive());
+        if packet.seq() < self.ack || self.recv_buffer.contains_key(&packet.seq()) {
+            return Ok(()); // a retransmission of a packet already received
+        }
+
+        if packet.is_rendezvous() {
+            let (channel, _) = self.rendezvous.as_mut().ok_or_else(|| {
+                Error::Internal("rendezvous packet received but rendezvous is disabled".to_string())
+            })?;
+            let remote: RemoteBuf = bincode::deserialize(&packet)?;
+            // the size prefix and checksum are pulled along with the message
+            let max_len = 8 + self.config.max_message_bytes + CHECKSUM_BYTES;
+            channel.start_pull(packet.seq(), remote, max_len)?;
+        }
+
+        // don't let packets waiting for reordering hold all recv buffers of the qp
+        if self.recv_buffer.len() >= HELD_RECV_BUFFERS / 2 {
+            packet.detach();
+        }
+        self.recv_buffer.insert(packet.seq(), packet);
+        Ok(())
+    }
+}
+
+/// Sends a stream of bytes reliably, the bytes are written straight into registered send slots
+///
+/// Bytes are packed into slots behind the room reserved for the packet header,
+/// a slot is sealed and posted as soon as it's full. At most the window size of the session packets
+/// are unacknowledged at a time, an unacknowledged packet is retransmitted by reposting its slot.
+struct Outgoing<'a> {
+    session: &'a mut Session,
+    max_data_bytes: usize,
+    /// the slot being filled and how many bytes of data are in it
+    filling: Option<(OwnedSlot, usize)>,
+    /// packets posted but not acknowledged yet, in the order of seq
+    inflight: VecDeque<PacketBuf>,
+    /// acknowledged seqs of packets that are not at the front of `inflight` yet
+    acked: BTreeSet<u64>,
+    last_progress: u64,
+    /// the error that made a write through `std::io::Write` fail
+    error: Option<Error>,
+    /// hashes the bytes written through `std::io::Write`, if the session uses checksums
+    checksum: Option<crc32fast::Hasher>,
+}
+
+impl<'a> Outgoing<'a> {
+    fn new(session: &'a mut Session) -> Self {
+        // packets may be smaller than the path mtu allows, if a smaller mtu is agreed on
+        let max_data_bytes = session
+            .transport
+            .max_data_bytes()
+            .min(max_data_bytes(session.config.mtu));
+        Self {
+            session,
+            max_data_bytes,
+            filling: None,
+            inflight: VecDeque::new(),
+            acked: BTreeSet::new(),
+            last_progress: now_micros(),
+            error: None,
+            checksum: None,
+        }
     }
 
-    fn make_packets(&mut self, bytes: Vec<u8>) -> Vec<Packet> {
-        bytes
-            .chunks(MAX_DATA_BYTES)
-            .map(|chunk| {
-                let packet = Packet::new(self.seq, self.id, chunk.to_vec());
-                self.seq += 1;
-                packet
-            })
-            .collect()
+    /// Write the checksum of the bytes written through `std::io::Write`
+    fn write_checksum(&mut self) -> Result<(), Error> {
+        match self.checksum.take() {
+            Some(hasher) => self.write_bytes(&hasher.finalize().to_be_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        while !bytes.is_empty() {
+            if self.filling.is_none() {
+                self.filling = Some((self.alloc_slot()?, 0));
+            }
+            let (slot, len) = self.filling.as_mut().unwrap();
+
+            // copy as many bytes as the slot can hold
+            let data = unsafe { slot.slot().as_mut_slice() };
+            let data =
+                &mut data[PACKET_HEADER_BYTES + *len..PACKET_HEADER_BYTES + self.max_data_bytes];
+            let n = data.len().min(bytes.len());
+            data[..n].copy_from_slice(&bytes[..n]);
+            *len += n;
+            bytes = &bytes[n..];
+
+            if *len == self.max_data_bytes {
+                self.seal()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a send slot, the slots may all be held by unacknowledged packets of this session
+    /// or of other streams on the transport, so keep polling acks until one is freed
+    fn alloc_slot(&mut self) -> Result<OwnedSlot, Error> {
+        loop {
+            if let Some(slot) = self.session.transport.try_alloc_send_slot()? {
+                return Ok(slot);
+            }
+            self.poll_acks()?;
+        }
+    }
+
+    /// Seal the slot being filled as a data packet and post it, once there is room in the window
+    fn seal(&mut self) -> Result<(), Error> {
+        self.seal_as(kind::DATA)
+    }
+
+    fn seal_as(&mut self, packet_kind: u8) -> Result<(), Error> {
+        let (slot, len) = match self.filling.take() {
+            Some(filling) => filling,
+            None => return Ok(()),
+        };
+
+        while self.inflight.len() >= self.session.config.window_size {
+            self.poll_acks()?;
+        }
+
+        let buf = PacketBuf::seal(slot, packet_kind, self.session.seq, self.session.id, len);
+        self.session.seq += 1;
+        self.session.transport.post_bufs(&[&buf])?;
+        // a reliable transport won't ack data packets, the slot is freed once the send completes
+        if !self.session.transport.is_reliable() || packet_kind == kind::RENDEZVOUS {
+            self.inflight.push_back(buf);
+        }
+        Ok(())
+    }
+
+    /// Send the last packet and wait until all packets are acknowledged
+    fn finish(mut self) -> Result<(), Error> {
+        self.seal()?;
+        while !self.inflight.is_empty() {
+            self.poll_acks()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for acks for one round, resend the window if nothing has been acknowledged for too long
+    fn poll_acks(&mut self) -> Result<(), Error> {
+        // recv acks, if reieved packets are not ack, insert them to recv_buffer and send back acks
+        let packets = self
+            .session
+            .transport
+            .recv_timeout(Some(self.session.poll_interval))?;
+        let oldest = self
+            .inflight
+            .front()
+            .map(|buf| buf.seq())
+            .unwrap_or(u64::MAX);
+        if !packets.is_empty() {
+            self.session.last_heard = now_micros();
+        }
+        let mut acks = vec![];
+        for packet in packets {
+            if packet.is_keepalive() {
+                continue;
+            }
+            if !packet.is_ack() {
+                if self.session.needs_ack(&packet) {
+                    acks.push(Packet::new_ack(packet.seq(), self.session.id));
+                }
+                self.session.insert_recv_buffer(packet)?;
+            } else if packet.ack() >= oldest {
+                self.acked.insert(packet.ack());
+            }
+        }
+        if !acks.is_empty() {
+            self.session.transport.send_burst(acks)?;
+        }
+        // the remote end may be waiting for a message of its own to be pulled
+        self.session.poll_pulls()?;
+
+        // try to move the window, acknowledged slots are freed once dropped
+        let mut moved = false;
+        while let Some(buf) = self.inflight.front() {
+            if !self.acked.remove(&buf.seq()) {
+                break;
+            }
+            self.inflight.pop_front();
+            moved = true;
+        }
+
+        if moved {
+            self.last_progress = now_micros();
+        } else if now_micros() - self.last_progress >= self.session.retransmit_timeout {
+            // resend the unacknowledged packets from their slots
+            let unacked: Vec<_> = self
+                .inflight
+                .iter()
+                .filter(|buf| !self.acked.contains(&buf.seq()))
+                .collect();
+            debug!("retransmit {} packets", unacked.len());
+            self.session.transport.post_bufs(&unacked)?;
+            self.last_progress = now_micros();
+        }
+        Ok(())
     }
+}
 
-    fn insert_recv_buffer(&mut self, packet: Packet) {
-        assert!(!packet.is_ack());
-        if packet.seq() >= self.ack {
-            self.recv_buffer.insert(packet.seq(), packet);
+impl std::io::Write for Outgoing<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(hasher) = self.checksum.as_mut() {
+            hasher.update(buf);
+        }
+        match self.write_bytes(buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(err) => {
+                let io_err = std::io::Error::other(err.to_string());
+                self.error = Some(err);
+                Err(io_err)
+            }
         }
     }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    extern crate std;
+    use alloc::sync::Arc;
 
     use super::*;
-    use crate::utils::tests::{new_random_data, new_two_transport};
+    use crate::{
+        rc::PreparedRc,
+        rendezvous::DEFAULT_RENDEZVOUS_THRESHOLD,
+        utils::tests::{new_random_data, new_test_context, new_two_transport},
+    };
 
     #[test]
     fn session_works() {
@@ -280,6 +871,111 @@ mod tests {
         s2_handle.join().unwrap();
     }
 
+    #[test]
+    // the message is read in place from the recv buffers
+    fn recv_message_zero_copy() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+
+        let bytes = new_random_data(16 * 1024);
+        let bytes_c = bytes.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(bytes).unwrap();
+        });
+
+        let s2_handle = std::thread::spawn(move || {
+            let message = s2.recv_message().unwrap();
+            assert!(message.chunks().count() > 1);
+            assert_eq!(message.to_vec(), bincode::serialize(&bytes_c).unwrap());
+            assert_eq!(message.deserialize::<Vec<u8>>().unwrap(), bytes_c);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+
+        assert!(s2.recv_timeout::<Vec<u8>>(Some(1000)).unwrap().is_none());
+        let bytes = new_random_data(16 * 1024);
+        let bytes_c = bytes.clone();
+        let s1_handle = std::thread::spawn(move || s1.send(bytes_c).unwrap());
+
+        // a message received across timeouts is kept until it's complete
+        let bytes_r = loop {
+            if let Some(bytes) = s2.recv_timeout::<Vec<u8>>(Some(10)).unwrap() {
+                break bytes;
+            }
+        };
+        assert_eq!(bytes_r, bytes);
+        s1_handle.join().unwrap();
+    }
+
+    #[test]
+    // large messages are pulled by the receiver instead of being fragmented
+    fn send_huge_rendezvous() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+        let context = new_test_context();
+        let rc1 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let rc2 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let (info1, info2) = (rc1.info(), rc2.info());
+        s1.enable_rendezvous(rc1.connect(info2).unwrap(), DEFAULT_RENDEZVOUS_THRESHOLD);
+        s2.enable_rendezvous(rc2.connect(info1).unwrap(), DEFAULT_RENDEZVOUS_THRESHOLD);
+
+        let huge_bytes = new_random_data(4 * 1024 * 1024); // 4MB
+        let huge_bytes_c = huge_bytes.clone();
+        let small_bytes = new_random_data(64);
+        let small_bytes_c = small_bytes.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(huge_bytes).unwrap();
+            s1.send(small_bytes).unwrap();
+        });
+
+        let s2_handle = std::thread::spawn(move || {
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), huge_bytes_c);
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), small_bytes_c);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+
+    #[test]
+    // both ends pull the message of the other while waiting for their own to be pulled
+    fn send_huge_rendezvous_both_ways() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+        let context = new_test_context();
+        let rc1 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let rc2 = PreparedRc::new(Arc::clone(&context), 1).unwrap();
+        let (info1, info2) = (rc1.info(), rc2.info());
+        s1.enable_rendezvous(rc1.connect(info2).unwrap(), DEFAULT_RENDEZVOUS_THRESHOLD);
+        s2.enable_rendezvous(rc2.connect(info1).unwrap(), DEFAULT_RENDEZVOUS_THRESHOLD);
+
+        let bytes1 = new_random_data(4 * 1024 * 1024); // 4MB
+        let bytes1_c = bytes1.clone();
+        let bytes2 = new_random_data(4 * 1024 * 1024); // 4MB
+        let bytes2_c = bytes2.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(bytes1).unwrap();
+            assert_eq!(s1.recv::<Vec<u8>>().unwrap(), bytes2_c);
+        });
+        let s2_handle = std::thread::spawn(move || {
+            s2.send(bytes2).unwrap();
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), bytes1_c);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+
     #[test]
     // send 1000 small packets
     fn send_small_packets() {
@@ -304,4 +1000,168 @@ mod tests {
         s1_handle.join().unwrap();
         s2_handle.join().unwrap();
     }
+
+    #[test]
+    fn agree_config() {
+        let server = SessionConfig {
+            window_size: 32,
+            max_message_bytes: 1 << 20,
+            ..Default::default()
+        };
+        let proposed = SessionConfig {
+            mtu: 1024,
+            checksum: true,
+            ..Default::default()
+        };
+        let agreed = server.agree(&proposed).unwrap();
+        assert_eq!(
+            agreed,
+            SessionConfig {
+                window_size: 32,
+                mtu: 1024,
+                checksum: true,
+                max_message_bytes: 1 << 20,
+                keepalive_micros: None,
+            }
+        );
+        assert!(proposed.check_agreed(&agreed).is_ok());
+        // the remote end may not agree on more than proposed
+        assert!(agreed.check_agreed(&proposed).is_err());
+        let no_checksum = SessionConfig {
+            checksum: false,
+            ..agreed.clone()
+        };
+        assert!(agreed.check_agreed(&no_checksum).is_err());
+
+        // keepalives are sent if either end asks, at the shorter interval if both do
+        let keepalive = SessionConfig {
+            keepalive_micros: Some(10_000),
+            ..proposed.clone()
+        };
+        let agreed = server.agree(&keepalive).unwrap();
+        assert_eq!(agreed.keepalive_micros, Some(10_000));
+        assert!(keepalive.check_agreed(&agreed).is_ok());
+        let server_keepalive = SessionConfig {
+            keepalive_micros: Some(5_000),
+            ..server.clone()
+        };
+        let agreed = server_keepalive.agree(&keepalive).unwrap();
+        assert_eq!(agreed.keepalive_micros, Some(5_000));
+        assert!(keepalive.check_agreed(&agreed).is_ok());
+        let no_keepalive = SessionConfig {
+            keepalive_micros: None,
+            ..agreed.clone()
+        };
+        assert!(keepalive.check_agreed(&no_keepalive).is_err());
+        let rare_keepalive = SessionConfig {
+            keepalive_micros: Some(20_000),
+            ..agreed.clone()
+        };
+        assert!(keepalive.check_agreed(&rare_keepalive).is_err());
+        let zero_keepalive = SessionConfig {
+            keepalive_micros: Some(0),
+            ..Default::default()
+        };
+        assert!(server.agree(&zero_keepalive).is_err());
+
+        let zero_window = SessionConfig {
+            window_size: 0,
+            ..Default::default()
+        };
+        assert!(server.agree(&zero_window).is_err());
+        let huge_mtu = SessionConfig {
+            mtu: MAX_MTU * 2,
+            ..Default::default()
+        };
+        assert!(server.agree(&huge_mtu).is_err());
+    }
+
+    #[test]
+    // both ends fill their windows at once, the acks still get send slots
+    fn full_windows_both_ways() {
+        let (tp1, tp2) = new_two_transport();
+        let (mut s1, mut s2) = (Session::new(0, tp1), Session::new(0, tp2));
+        assert_eq!(s1.config().window_size + ACK_SEND_SLOTS, DEFAULT_POOL_SIZE);
+
+        let bytes1 = new_random_data(1024 * 1024);
+        let bytes2 = new_random_data(1024 * 1024);
+        let (bytes1_c, bytes2_c) = (bytes1.clone(), bytes2.clone());
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(bytes1).unwrap();
+            assert_eq!(s1.recv::<Vec<u8>>().unwrap(), bytes2_c);
+        });
+        let s2_handle = std::thread::spawn(move || {
+            s2.send(bytes2).unwrap();
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), bytes1_c);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
+
+    #[test]
+    // ends waiting for packets keep hearing from each other while no messages are sent
+    fn keepalive() {
+        let config = SessionConfig {
+            keepalive_micros: Some(1000),
+            ..Default::default()
+        };
+        let (tp1, tp2) = new_two_transport();
+        let mut s1 = Session::with_config(0, tp1, config.clone());
+        let mut s2 = Session::with_config(0, tp2, config);
+
+        let handles = [
+            std::thread::spawn(move || {
+                assert!(s1.recv_message_timeout(Some(50_000)).unwrap().is_none());
+                s1.silent_micros()
+            }),
+            std::thread::spawn(move || {
+                assert!(s2.recv_message_timeout(Some(50_000)).unwrap().is_none());
+                s2.silent_micros()
+            }),
+        ];
+        for handle in handles {
+            assert!(handle.join().unwrap() < 20_000);
+        }
+    }
+
+    #[test]
+    // messages are sent in smaller packets with a small window and checked on arrival
+    fn send_with_config() {
+        let config = SessionConfig {
+            window_size: 4,
+            mtu: MIN_MTU,
+            checksum: true,
+            max_message_bytes: 1024 * 1024,
+            keepalive_micros: None,
+        };
+        let (tp1, tp2) = new_two_transport();
+        let mut s1 = Session::with_config(0, tp1, config.clone());
+        let mut s2 = Session::with_config(0, tp2, config);
+
+        let bytes = new_random_data(64 * 1024);
+        let bytes_c = bytes.clone();
+
+        let s1_handle = std::thread::spawn(move || {
+            s1.send(bytes).unwrap();
+            let err = s1.send(new_random_data(2 * 1024 * 1024)).unwrap_err();
+            assert!(matches!(err, Error::TooLarge(_, 1048576)));
+            s1.set_codec(CodecKind::Postcard);
+            s1.send(vec![1u8, 2, 3]).unwrap();
+        });
+
+        let s2_handle = std::thread::spawn(move || {
+            let message = s2.recv_message().unwrap();
+            assert!(message
+                .chunks()
+                .all(|chunk| chunk.len() <= max_data_bytes(MIN_MTU)));
+            assert_eq!(message.deserialize::<Vec<u8>>().unwrap(), bytes_c);
+            s2.set_codec(CodecKind::Postcard);
+            assert_eq!(s2.recv::<Vec<u8>>().unwrap(), [1, 2, 3]);
+        });
+
+        s1_handle.join().unwrap();
+        s2_handle.join().unwrap();
+    }
 }
@@ -0,0 +1,490 @@
+use alloc::{format, sync::Arc, vec::Vec};
+use core::ops::Range;
+
+use spin::Mutex;
+use tracing::{debug, warn};
+use KRdmaKit::{context::Context, MemoryRegion};
+
+use crate::error::Error;
+
+/// Occupancy statistics of a slot pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// How many slots have been registered
+    pub allocated: usize,
+    /// How many slots are being used now
+    pub in_use: usize,
+    /// The pool never grows beyond this many slots
+    pub limit: usize,
+    /// The largest `in_use` ever seen
+    pub high_watermark: usize,
+    /// How many times a slot was requested while the pool was exhausted
+    pub exhausted: u64,
+}
+
+/// Anonymous memory backed by huge pages
+struct HugePages {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only accessed through the memory region registered on it
+unsafe impl Send for HugePages {}
+unsafe impl Sync for HugePages {}
+
+impl HugePages {
+    const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    /// Map at least `len` bytes, returns `None` if no huge pages are available
+    #[cfg(feature = "user")]
+    fn map(len: usize) -> Option<Self> {
+        let len = len.next_multiple_of(Self::HUGE_PAGE_SIZE);
+        let ptr = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                -1,
+                0,
+            )
+        };
+        (ptr != libc::MAP_FAILED).then(|| Self { ptr: ptr as _, len })
+    }
+
+    // TODO: in kernel, allocate from the huge page pool
+    #[cfg(not(feature = "user"))]
+    fn map(_len: usize) -> Option<Self> {
+        None
+    }
+}
+
+impl Drop for HugePages {
+    #[cfg(feature = "user")]
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as _, self.len);
+        }
+    }
+
+    #[cfg(not(feature = "user"))]
+    fn drop(&mut self) {}
+}
+
+/// One contiguous registered memory region that is sliced into `slot_size` slots
+pub(crate) struct Slab {
+    mr: Arc<MemoryRegion>,
+    slot_size: u64,
+    /// The mapping backing `mr` if it's huge page backed, unmapped after `mr` is deregistered
+    _mapping: Option<HugePages>,
+}
+
+impl Slab {
+    fn new(
+        context: &Arc<Context>,
+        slots: usize,
+        slot_size: u64,
+        huge_pages: bool,
+    ) -> Result<Self, Error> {
+        let len = slots * slot_size as usize;
+
+        if huge_pages {
+            match HugePages::map(len) {
+                Some(mapping) => {
+                    let mr =
+                        MemoryRegion::new_from_raw(Arc::clone(context), mapping.ptr, mapping.len)
+                            .map_err(|err| {
+                            Error::Internal(format!("failed to register slab, {err}"))
+                        })?;
+                    return Ok(Self {
+                        mr: Arc::new(mr),
+                        slot_size,
+                        _mapping: Some(mapping),
+                    });
+                }
+                None => warn!("no huge pages available, fall back to normal pages"),
+            }
+        }
+
+        let mr = MemoryRegion::new(Arc::clone(context), len)
+            .map_err(|err| Error::Internal(format!("failed to register slab, {err}")))?;
+        Ok(Self {
+            mr: Arc::new(mr),
+            slot_size,
+            _mapping: None,
+        })
+    }
+}
+
+/// A `slot_size` slice of a slab, identified by `id` in its pool
+pub(crate) struct Slot {
+    pub(crate) id: u64,
+    slab: Arc<Slab>,
+    offset: u64,
+}
+
+impl Slot {
+    /// The memory region the slot lives in
+    pub(crate) fn mr(&self) -> &Arc<MemoryRegion> {
+        &self.slab.mr
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.slab.slot_size
+    }
+
+    /// Range of the first `len` bytes of the slot, relative to the start of its mr
+    pub(crate) fn range(&self, len: u64) -> Range<u64> {
+        debug_assert!(len <= self.size());
+        self.offset..self.offset + len
+    }
+
+    /// Range of the whole slot, relative to the start of its mr
+    pub(crate) fn full_range(&self) -> Range<u64> {
+        self.range(self.size())
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        (self.slab.mr.get_virt_addr() as u64 + self.offset) as *mut u8
+    }
+
+    /// # Safety
+    /// The slot must not be posted to the nic while the slice is alive
+    #[allow(clippy::mut_from_ref)] // the slot is a handle to memory the nic writes, not its owner
+    pub(crate) unsafe fn as_mut_slice(&self) -> &mut [u8] {
+        alloc::slice::from_raw_parts_mut(self.as_ptr(), self.size() as usize)
+    }
+}
+
+/// A slot taken from a shared pool, given back to the pool when dropped
+pub struct OwnedSlot {
+    slot: Slot,
+    pool: Arc<Mutex<SlabPool>>,
+}
+
+impl OwnedSlot {
+    /// Take a free slot from `pool`, returns `None` if the pool is exhausted
+    pub(crate) fn alloc(pool: &Arc<Mutex<SlabPool>>) -> Option<Self> {
+        Self::alloc_leaving(pool, 0)
+    }
+
+    /// Like `alloc`, but leave `reserve` free slots of `pool` to others
+    pub(crate) fn alloc_leaving(pool: &Arc<Mutex<SlabPool>>, reserve: usize) -> Option<Self> {
+        let slot = pool.lock().get_free_slot_leaving(reserve)?;
+        Some(Self {
+            slot,
+            pool: Arc::clone(pool),
+        })
+    }
+
+    pub(crate) fn slot(&self) -> &Slot {
+        &self.slot
+    }
+
+    pub(crate) fn mark_posted(&self) {
+        self.pool.lock().mark_slot_posted(self.slot.id)
+    }
+
+    /// Whether the slot is still in the send queue
+    pub(crate) fn is_posted(&self) -> bool {
+        self.pool.lock().is_slot_posted(self.slot.id)
+    }
+}
+
+impl Drop for OwnedSlot {
+    fn drop(&mut self) {
+        if let Err(err) = self.pool.lock().mark_slot_free(self.slot.id) {
+            warn!("failed to free slot, {err}");
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct SlotState {
+    /// whether someone holds the slot
+    owned: bool,
+    /// how many work requests on the slot haven't completed yet
+    posted: u32,
+}
+
+/// A pool of equally sized slots sliced from a few large registered slabs
+///
+/// Registering one slab instead of one mr per packet buffer keeps the registration cost and
+/// the nic translation entries low. Free slots are kept in a free list so getting and freeing
+/// a slot is O(1), and the id of a slot is used as the `wr_id` of its work request.
+/// When the free list is empty, the pool grows by registering a new slab as large as all
+/// existing ones, until it reaches `limit` slots.
+///
+/// A slot is free only after its owner released it and all its posted work requests completed,
+/// so a send slot can be posted again for retransmission while it's still in the send queue.
+pub(crate) struct SlabPool {
+    context: Arc<Context>,
+    slot_size: u64,
+    huge_pages: bool,
+    slabs: Vec<Arc<Slab>>,
+    /// slab index and offset of every slot
+    slots: Vec<(usize, u64)>,
+    /// state of the slot of the same index
+    states: Vec<SlotState>,
+    /// ids of the free slots
+    free: Vec<u64>,
+    limit: usize,
+    high_watermark: usize,
+    exhausted: u64,
+}
+
+impl SlabPool {
+    /// Create a pool of `num` slots of `slot_size` bytes that never grows
+    pub(crate) fn new(
+        context: Arc<Context>,
+        num: usize,
+        slot_size: u64,
+        huge_pages: bool,
+    ) -> Result<Self, Error> {
+        Self::with_limit(context, num, num, slot_size, huge_pages)
+    }
+
+    /// Create a pool of `num` slots of `slot_size` bytes that may grow up to `limit` slots
+    pub(crate) fn with_limit(
+        context: Arc<Context>,
+        num: usize,
+        limit: usize,
+        slot_size: u64,
+        huge_pages: bool,
+    ) -> Result<Self, Error> {
+        assert!(
+            num <= limit,
+            "initial pool size {num} exceeds the limit {limit}"
+        );
+        let mut pool = Self {
+            context,
+            slot_size,
+            huge_pages,
+            slabs: Vec::new(),
+            slots: Vec::with_capacity(num),
+            states: Vec::with_capacity(num),
+            free: Vec::with_capacity(num),
+            limit,
+            high_watermark: 0,
+            exhausted: 0,
+        };
+        pool.grow(num)?;
+        Ok(pool)
+    }
+
+    /// Register a slab of `n` more slots and put them in the free list
+    fn grow(&mut self, n: usize) -> Result<(), Error> {
+        if n == 0 {
+            return Ok(());
+        }
+
+        let slab = Slab::new(&self.context, n, self.slot_size, self.huge_pages)?;
+        let slab_idx = self.slabs.len();
+        self.slabs.push(Arc::new(slab));
+        for i in 0..n {
+            self.free.push(self.slots.len() as u64);
+            self.slots.push((slab_idx, i as u64 * self.slot_size));
+            self.states.push(SlotState::default());
+        }
+        Ok(())
+    }
+
+    fn slot(&self, id: u64) -> Slot {
+        let (slab_idx, offset) = self.slots[id as usize];
+        Slot {
+            id,
+            slab: Arc::clone(&self.slabs[slab_idx]),
+            offset,
+        }
+    }
+
+    pub(crate) fn get_free_slot(&mut self) -> Option<Slot> {
+        self.get_free_slot_leaving(0)
+    }
+
+    /// Like `get_free_slot`, but only take a slot if `reserve` free ones are left after it
+    pub(crate) fn get_free_slot_leaving(&mut self, reserve: usize) -> Option<Slot> {
+        if self.free.len() <= reserve && self.slots.len() < self.limit {
+            let n = self.slots.len().max(1).min(self.limit - self.slots.len());
+            match self.grow(n) {
+                Ok(()) => debug!("slab pool grows to {} slots", self.slots.len()),
+                Err(err) => warn!("failed to grow slab pool, {err}"),
+            }
+        }
+
+        if self.free.len() <= reserve {
+            self.exhausted += 1;
+            return None;
+        }
+        let id = self.free.pop().unwrap();
+        self.states[id as usize].owned = true;
+        self.high_watermark = self.high_watermark.max(self.in_use());
+        Some(self.slot(id))
+    }
+
+    /// The owner gives up the slot, it's free once all its work requests completed
+    pub(crate) fn mark_slot_free(&mut self, id: u64) -> Result<(), Error> {
+        match self.states.get_mut(id as usize) {
+            Some(state) if state.owned => {
+                state.owned = false;
+                if state.posted == 0 {
+                    self.free.push(id);
+                }
+                Ok(())
+            }
+            Some(_) => Err(Error::Internal(format!("slot of id {id} is already free"))),
+            None => Err(Error::Internal(format!("slot of id {id} doesn't exist"))),
+        }
+    }
+
+    /// A work request on the slot has been posted
+    pub(crate) fn mark_slot_posted(&mut self, id: u64) {
+        self.states[id as usize].posted += 1;
+    }
+
+    /// A work request on the slot has completed
+    pub(crate) fn mark_slot_completed(&mut self, id: u64) -> Result<(), Error> {
+        match self.states.get_mut(id as usize) {
+            Some(state) if state.posted > 0 => {
+                state.posted -= 1;
+                if state.posted == 0 && !state.owned {
+                    self.free.push(id);
+                }
+                Ok(())
+            }
+            Some(_) => Err(Error::Internal(format!("slot of id {id} isn't posted"))),
+            None => Err(Error::Internal(format!("slot of id {id} doesn't exist"))),
+        }
+    }
+
+    /// Whether some work requests on the slot haven't completed yet
+    pub(crate) fn is_slot_posted(&self, id: u64) -> bool {
+        self.states[id as usize].posted > 0
+    }
+
+    pub(crate) fn get_slot(&self, id: u64) -> Result<Slot, Error> {
+        if (id as usize) < self.slots.len() {
+            Ok(self.slot(id))
+        } else {
+            Err(Error::Internal(format!("slot of id {id} doesn't exist")))
+        }
+    }
+
+    pub(crate) fn slot_size(&self) -> u64 {
+        self.slot_size
+    }
+
+    /// Number of slots that are free now
+    pub(crate) fn free(&self) -> usize {
+        self.free.len()
+    }
+
+    fn in_use(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// Allow the pool to grow up to `limit` slots, it never shrinks below the slots already registered
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.max(self.slots.len());
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocated: self.slots.len(),
+            in_use: self.in_use(),
+            limit: self.limit,
+            high_watermark: self.high_watermark,
+            exhausted: self.exhausted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PoolStats, SlabPool};
+    use crate::utils::tests::new_test_context;
+
+    const SLOT_SIZE: u64 = 1024;
+
+    #[test]
+    fn slab_pool_free_list() {
+        let mut pool = SlabPool::new(new_test_context(), 2, SLOT_SIZE, false).unwrap();
+
+        let slot1 = pool.get_free_slot().unwrap();
+        let slot2 = pool.get_free_slot().unwrap();
+        assert_ne!(slot1.id, slot2.id);
+        assert!(pool.get_free_slot().is_none());
+
+        // slots of the same slab don't overlap
+        assert_eq!(slot1.mr().get_virt_addr(), slot2.mr().get_virt_addr());
+        assert!(
+            slot1.full_range().end <= slot2.full_range().start
+                || slot2.full_range().end <= slot1.full_range().start
+        );
+
+        pool.mark_slot_free(slot1.id).unwrap();
+        assert!(pool.mark_slot_free(slot1.id).is_err());
+        assert_eq!(pool.get_free_slot().unwrap().id, slot1.id);
+
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                allocated: 2,
+                in_use: 2,
+                limit: 2,
+                high_watermark: 2,
+                exhausted: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn slab_pool_posted_slot() {
+        let mut pool = SlabPool::new(new_test_context(), 1, SLOT_SIZE, false).unwrap();
+
+        // a released slot stays in use until its work requests complete
+        let slot = pool.get_free_slot().unwrap();
+        pool.mark_slot_posted(slot.id);
+        pool.mark_slot_posted(slot.id);
+        pool.mark_slot_free(slot.id).unwrap();
+        assert!(pool.get_free_slot().is_none());
+
+        pool.mark_slot_completed(slot.id).unwrap();
+        assert!(pool.get_free_slot().is_none());
+        pool.mark_slot_completed(slot.id).unwrap();
+        assert!(pool.mark_slot_completed(slot.id).is_err());
+        assert_eq!(pool.get_free_slot().unwrap().id, slot.id);
+    }
+
+    #[test]
+    fn slab_pool_reserve() {
+        let mut pool = SlabPool::new(new_test_context(), 2, SLOT_SIZE, false).unwrap();
+
+        // the last free slot is only taken by those who don't leave it to others
+        assert!(pool.get_free_slot_leaving(1).is_some());
+        assert!(pool.get_free_slot_leaving(1).is_none());
+        assert!(pool.get_free_slot().is_some());
+    }
+
+    #[test]
+    fn slab_pool_grow() {
+        let mut pool = SlabPool::with_limit(new_test_context(), 1, 3, SLOT_SIZE, false).unwrap();
+
+        for _ in 0..3 {
+            assert!(pool.get_free_slot().is_some());
+        }
+        assert!(pool.get_free_slot().is_none());
+
+        let stats = pool.stats();
+        assert_eq!(stats.allocated, 3);
+        assert_eq!(stats.in_use, 3);
+    }
+
+    #[test]
+    fn slab_pool_huge_pages() {
+        // falls back to normal pages if the machine has no huge pages configured
+        let mut pool = SlabPool::with_limit(new_test_context(), 64, 64, SLOT_SIZE, true).unwrap();
+        let slot = pool.get_free_slot().unwrap();
+        unsafe { slot.as_mut_slice() }.fill(0xff);
+    }
+}
@@ -0,0 +1,235 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    sync::Arc,
+    vec::Vec,
+};
+
+use spin::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    error::Error,
+    messages::{Packet, PacketBuf, RecvBuf},
+    slab::OwnedSlot,
+    transport::PacketTransport,
+    wait::{poll_until, CqEvents, WaitMode},
+};
+
+/// Independent streams of a session over one transport
+///
+/// Every stream runs its own [`Session`](crate::session::Session), with its own sequence space
+/// and reassembly state, so a bulk transfer on one stream doesn't hold back small messages on
+/// another. Packets carry their stream id and are demultiplexed to the inbox of their stream.
+///
+/// There is no in-flight budget shared by the streams: each session has a window of its own, so
+/// n streams may have n windows of packets unacked at once. The only bound on all of them is the
+/// send slots of the transport, a packet holds its slot until it's acked. A stream that runs out
+/// of slots keeps polling its acks until some are freed.
+///
+/// The mux works on any [`PacketTransport`], but the `Server` and `Client` of rdma-rpc don't use it,
+/// they run a single session per connection.
+pub struct StreamMux<P> {
+    inner: Mutex<MuxInner<P>>,
+}
+
+struct MuxInner<P> {
+    transport: P,
+    /// stream id to packets received but not yet consumed by the stream
+    inboxes: BTreeMap<u32, VecDeque<RecvBuf>>,
+}
+
+impl<P: PacketTransport> MuxInner<P> {
+    /// Poll the transport on behalf of stream `polling` and dispatch packets to streams
+    ///
+    /// Packets of other streams are copied out of their recv buffers, like on a shared qp.
+    fn poll(&mut self, polling: u32) -> Result<(), Error> {
+        for mut packet in self.transport.try_recv()? {
+            match self.inboxes.get_mut(&packet.stream_id()) {
+                Some(inbox) => {
+                    if packet.stream_id() != polling {
+                        packet.detach();
+                    }
+                    inbox.push_back(packet)
+                }
+                None => warn!(
+                    "drop packet of unknown stream {}, seq: {}",
+                    packet.stream_id(),
+                    packet.seq()
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P: PacketTransport> StreamMux<P> {
+    pub fn new(transport: P) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(MuxInner {
+                transport,
+                inboxes: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Open stream `stream_id`, the remote end must open the same stream to talk on it
+    pub fn open_stream(self: &Arc<Self>, stream_id: u32) -> Result<StreamPort<P>, Error> {
+        let mut inner = self.inner.lock();
+        if inner.inboxes.contains_key(&stream_id) {
+            return Err(Error::Internal(format!(
+                "stream {stream_id} already exists"
+            )));
+        }
+        inner.inboxes.insert(stream_id, VecDeque::new());
+        debug!(
+            "stream {stream_id} opened, {} streams in total",
+            inner.inboxes.len()
+        );
+
+        Ok(StreamPort {
+            mux: Arc::clone(self),
+            stream_id,
+            events: inner.transport.cq_events(),
+            wait_mode: WaitMode::default(),
+        })
+    }
+
+    /// Number of streams currently open
+    pub fn streams(&self) -> usize {
+        self.inner.lock().inboxes.len()
+    }
+}
+
+/// The view of a [`StreamMux`] owned by a single stream, closes the stream when dropped
+pub struct StreamPort<P> {
+    mux: Arc<StreamMux<P>>,
+    stream_id: u32,
+    /// events of the transport, waited on without holding its lock
+    events: Option<CqEvents>,
+    wait_mode: WaitMode,
+}
+
+impl<P> StreamPort<P> {
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    pub fn set_wait_mode(&mut self, mode: WaitMode) {
+        self.wait_mode = mode;
+    }
+}
+
+impl<P: PacketTransport> PacketTransport for StreamPort<P> {
+    fn send_burst(&mut self, mut packets: Vec<Packet>) -> Result<(), Error> {
+        for packet in packets.iter_mut() {
+            packet.set_stream_id(self.stream_id);
+        }
+        // the transport keeps slots for these, so it's locked only until earlier sends complete
+        self.mux.inner.lock().transport.send_burst(packets)
+    }
+
+    fn recv_timeout(&mut self, timeout_micros: Option<u64>) -> Result<Vec<RecvBuf>, Error> {
+        let events = self.events.clone();
+        let packets = poll_until(self.wait_mode, events.as_ref(), timeout_micros, || {
+            let packets = self.try_recv()?;
+            Ok((!packets.is_empty()).then_some(packets))
+        })?;
+        Ok(packets.unwrap_or_default())
+    }
+
+    fn try_recv(&mut self) -> Result<Vec<RecvBuf>, Error> {
+        let mut inner = self.mux.inner.lock();
+        inner.poll(self.stream_id)?;
+        let inbox = inner
+            .inboxes
+            .get_mut(&self.stream_id)
+            .ok_or_else(|| Error::Internal(format!("stream {} isn't open", self.stream_id)))?;
+        Ok(inbox.drain(..).collect())
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        self.mux.inner.lock().transport.max_data_bytes()
+    }
+
+    fn try_alloc_send_slot(&mut self) -> Result<Option<OwnedSlot>, Error> {
+        self.mux.inner.lock().transport.try_alloc_send_slot()
+    }
+
+    fn post_bufs(&mut self, bufs: &[&PacketBuf]) -> Result<(), Error> {
+        for buf in bufs {
+            if !buf.slot().is_posted() {
+                buf.set_stream_id(self.stream_id);
+            }
+        }
+        self.mux.inner.lock().transport.post_bufs(bufs)
+    }
+
+    fn is_reliable(&self) -> bool {
+        self.mux.inner.lock().transport.is_reliable()
+    }
+
+    fn cq_events(&self) -> Option<CqEvents> {
+        self.events.clone()
+    }
+}
+
+impl<P> Drop for StreamPort<P> {
+    fn drop(&mut self) {
+        self.mux.inner.lock().inboxes.remove(&self.stream_id);
+        debug!("stream {} closed", self.stream_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::StreamMux;
+    use crate::{
+        session::Session,
+        utils::tests::{new_random_data, new_two_transport},
+    };
+
+    #[test]
+    // small messages on one stream go on while a bulk transfer is in progress on another
+    fn streams_are_independent() {
+        const N_ROUNDS: usize = 100;
+        let (tp1, tp2) = new_two_transport();
+        let (mux1, mux2) = (StreamMux::new(tp1), StreamMux::new(tp2));
+        let mut bulk1 = Session::new(0, mux1.open_stream(0).unwrap());
+        let mut bulk2 = Session::new(0, mux2.open_stream(0).unwrap());
+        let mut small1 = Session::new(0, mux1.open_stream(1).unwrap());
+        let mut small2 = Session::new(0, mux2.open_stream(1).unwrap());
+        assert!(mux1.open_stream(1).is_err());
+        assert_eq!(mux1.streams(), 2);
+
+        let huge_bytes = new_random_data(4 * 1024 * 1024); // 4MB
+        let huge_bytes_c = huge_bytes.clone();
+        let small_bytes = new_random_data(64);
+        let small_bytes_c = small_bytes.clone();
+
+        let handles = [
+            std::thread::spawn(move || bulk1.send(huge_bytes).unwrap()),
+            std::thread::spawn(move || {
+                assert_eq!(bulk2.recv::<Vec<u8>>().unwrap(), huge_bytes_c);
+            }),
+            std::thread::spawn(move || {
+                for _ in 0..N_ROUNDS {
+                    small1.send(small_bytes.clone()).unwrap();
+                    assert_eq!(small1.recv::<Vec<u8>>().unwrap(), small_bytes);
+                }
+            }),
+            std::thread::spawn(move || {
+                for _ in 0..N_ROUNDS {
+                    let bytes = small2.recv::<Vec<u8>>().unwrap();
+                    assert_eq!(bytes, small_bytes_c);
+                    small2.send(bytes).unwrap();
+                }
+            }),
+        ];
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
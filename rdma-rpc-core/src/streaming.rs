@@ -0,0 +1,204 @@
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+};
+use core::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{error::Error, session::Session};
+
+/// Items either end of a stream may send before the receiver grants more credits,
+/// the receiver grants them back in batches of half of the window
+const STREAM_WINDOW: usize = 16;
+
+/// What goes over a session during a streaming call
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum Frame<T> {
+    Item(T),
+    /// the receiver consumed this many items, the sender may send as many more
+    Credit(u32),
+    /// the sender has no more items
+    End,
+    /// the sender failed, it has no more items either
+    Error(String),
+    /// the sender sent its end and received the end of the other side, nothing follows
+    Closed,
+}
+
+/// One end of a streaming call, sends items of `S` and receives items of `R`
+///
+/// Both ends may send any number of items, so the same call serves server-streaming,
+/// client-streaming and bidirectional RPCs. Each end half-closes its stream with `close_send`,
+/// and the call is over once both ends did. A sender never has more than `STREAM_WINDOW`
+/// items unconsumed by the receiver, it waits for credits before sending more.
+///
+/// The call borrows the session, which can't be used for other calls until it's finished.
+/// Dropping an unfinished call finishes it, discarding the items not received yet.
+pub struct StreamCall<'a, S, R>
+where
+    S: Serialize + Clone,
+    R: DeserializeOwned,
+{
+    session: &'a mut Session,
+    /// items the remote end is still willing to take
+    send_credits: usize,
+    /// items consumed since credits were last granted
+    consumed: usize,
+    /// frames received while waiting for credits
+    pending: VecDeque<Frame<R>>,
+    send_closed: bool,
+    recv_closed: bool,
+    finished: bool,
+    phantom: PhantomData<S>,
+}
+
+impl<'a, S, R> StreamCall<'a, S, R>
+where
+    S: Serialize + Clone,
+    R: DeserializeOwned,
+{
+    pub(crate) fn new(session: &'a mut Session) -> Self {
+        Self {
+            session,
+            send_credits: STREAM_WINDOW,
+            consumed: 0,
+            pending: VecDeque::new(),
+            send_closed: false,
+            recv_closed: false,
+            finished: false,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Send an item, waiting for credits if the remote end has not consumed earlier ones yet
+    pub fn send(&mut self, item: S) -> Result<(), Error> {
+        if self.send_closed {
+            return Err(Error::Internal("send on a closed stream".to_string()));
+        }
+        while self.send_credits == 0 {
+            match self.session.recv::<Frame<R>>()? {
+                Frame::Credit(n) => self.send_credits += n as usize,
+                frame => self.pending.push_back(frame),
+            }
+        }
+        self.send_credits -= 1;
+        self.session.send(Frame::Item(item))
+    }
+
+    /// Tell the remote end that no more items follow
+    pub fn close_send(&mut self) -> Result<(), Error> {
+        self.end_with(Frame::End)
+    }
+
+    /// Close the stream of items with an error, which the remote end receives instead of more items
+    pub(crate) fn fail(&mut self, reason: String) -> Result<(), Error> {
+        self.end_with(Frame::Error(reason))
+    }
+
+    fn end_with(&mut self, frame: Frame<S>) -> Result<(), Error> {
+        if self.send_closed {
+            return Ok(());
+        }
+        self.send_closed = true;
+        self.session.send(frame)
+    }
+
+    /// Receive the next item, returns `None` once the remote end closed its stream
+    ///
+    /// An error the remote end closed its stream with is returned as [`Error::Remote`].
+    pub fn recv(&mut self) -> Result<Option<R>, Error> {
+        if self.recv_closed {
+            return Ok(None);
+        }
+        match self.next_frame()? {
+            Frame::Item(item) => {
+                self.consumed += 1;
+                if self.consumed >= STREAM_WINDOW / 2 {
+                    self.session
+                        .send(Frame::<S>::Credit(self.consumed as u32))?;
+                    self.consumed = 0;
+                }
+                Ok(Some(item))
+            }
+            Frame::End => {
+                self.recv_closed = true;
+                Ok(None)
+            }
+            Frame::Error(reason) => {
+                self.recv_closed = true;
+                Err(Error::Remote(reason))
+            }
+            Frame::Credit(_) | Frame::Closed => {
+                Err(Error::Internal("unexpected frame in a stream".to_string()))
+            }
+        }
+    }
+
+    /// Close the stream, discard the items not received yet and wait until the remote end is done
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.close_send()?;
+        while !self.recv_closed {
+            match self.recv() {
+                Ok(_) | Err(Error::Remote(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // credits are only granted before the end of the remote end, so none follow `Closed`
+        self.session.send(Frame::<S>::Closed)?;
+        loop {
+            if let Frame::Closed = self.next_frame()? {
+                break;
+            }
+        }
+        self.finished = true;
+        debug!("stream call finished");
+        Ok(())
+    }
+
+    /// The next frame that isn't a credit, credits are taken in on the way
+    fn next_frame(&mut self) -> Result<Frame<R>, Error> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(frame);
+        }
+        loop {
+            match self.session.recv::<Frame<R>>()? {
+                Frame::Credit(n) => self.send_credits += n as usize,
+                frame => return Ok(frame),
+            }
+        }
+    }
+}
+
+impl<S, R> Iterator for StreamCall<'_, S, R>
+where
+    S: Serialize + Clone,
+    R: DeserializeOwned,
+{
+    type Item = Result<R, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().transpose()
+    }
+}
+
+impl<S, R> Drop for StreamCall<'_, S, R>
+where
+    S: Serialize + Clone,
+    R: DeserializeOwned,
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            warn!("failed to finish a dropped stream call, {err}");
+        }
+    }
+}
@@ -1,187 +1,296 @@
-use alloc::{collections::BTreeMap, format, string::ToString, sync::Arc, vec::Vec};
+use alloc::{format, string::ToString, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use tracing::{debug, error, info};
+use spin::Mutex;
+use tracing::{debug, error, info, warn};
 use KRdmaKit::{
     context::Context,
     services_user::{self},
-    DatagramEndpoint, MemoryRegion, QueuePair, QueuePairBuilder,
+    DatagramEndpoint, QueuePair, QueuePairBuilder,
 };
 
 use crate::{
     error::Error,
-    messages::{Packet, QPInfo},
+    messages::{Packet, PacketBuf, QPInfo, RecvBuf, PACKET_HEADER_BYTES},
+    recv_pool::SharedRecvPool,
+    rendezvous::{ReadCompletions, READ_WR_ID},
+    slab::{OwnedSlot, PoolStats, SlabPool, Slot},
+    utils::Backoff,
+    wait::{poll_until, CqEvents, WaitMode},
 };
-pub const MTU: u64 = 1024;
-const BUF_SIZE: u64 = MTU; // 4KB
+pub const MIN_MTU: u64 = 256; // the smallest mtu an IB/RoCE port can run at
+pub const MAX_MTU: u64 = 4096; // the largest mtu an IB/RoCE port can run at
 const UD_DATA_OFFSET: usize = 40; // for a UD message, the first 40 bytes are reserved for GRH
-const MAX_PACKET_BYTES: usize = BUF_SIZE as usize - UD_DATA_OFFSET;
-pub(crate) const MAX_DATA_BYTES: usize = MAX_PACKET_BYTES - 33; // reserve for packet meta
-const POOL_SIZE: u8 = 64; // how many mrs are there in a mr pool
+pub(crate) const POOL_SIZE: u8 = 64; // the most completions polled at once
+pub const DEFAULT_POOL_SIZE: usize = POOL_SIZE as usize; // how many slots are there in a slab pool
+pub const MIN_POOL_SIZE: usize = 64; // a session may hold up to 48 recv buffers of its qp
+pub const MAX_POOL_SIZE: usize = 4096; // common nics allow this many outstanding work requests on a qp
+pub const ACK_SEND_SLOTS: usize = 1; // send slots a window never takes, so acks are always sent
+
+/// Settings of the qps a transport creates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QpConfig {
+    /// send slots of a qp, and recv buffers posted to it unless they are borrowed from a shared pool
+    pub pool_size: usize,
+    /// the entry of the gid table of the port that addresses the qp, RoCE ports have several
+    pub gid_index: usize,
+    /// back the send slots and the recv buffers owned by the qp with huge pages if there are any
+    pub huge_pages: bool,
+}
+
+impl Default for QpConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: DEFAULT_POOL_SIZE,
+            gid_index: 0,
+            huge_pages: false,
+        }
+    }
+}
+
+/// How many bytes a serialized packet may take on a path of `mtu`
+pub(crate) fn max_packet_bytes(mtu: u64) -> usize {
+    mtu as usize - UD_DATA_OFFSET
+}
 
-struct MemoryRegionWrapper {
-    mr: Arc<MemoryRegion>,
-    used: bool, // whether it's being used now or free
+/// How many bytes of user data fit in a packet on a path of `mtu`
+pub fn max_data_bytes(mtu: u64) -> usize {
+    max_packet_bytes(mtu) - PACKET_HEADER_BYTES
 }
 
-struct MrPool {
-    mrs: BTreeMap<u64, MemoryRegionWrapper>,
+/// Query the active mtu of `port`
+///
+/// Every recv buffer of the port is as large as this mtu, so a datagram of this size always fits.
+pub fn query_mtu(context: &Arc<Context>, port: u8) -> Result<u64, Error> {
+    let attr = context
+        .get_port_attr(port)
+        .map_err(|err| Error::Internal(format!("failed to query port {port}, {err}")))?;
+    // ibv_mtu enumerates 256, 512, 1024, 2048 and 4096 starting from 1
+    let mtu = 128u64 << (attr.active_mtu as u64);
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(Error::Internal(format!(
+            "unexpected active mtu {} of port {port}",
+            attr.active_mtu as u64
+        )));
+    }
+    debug!("active mtu of port {port}: {mtu}");
+    Ok(mtu)
+}
+
+/// Where the recv buffers of a qp come from
+enum RecvBuffers {
+    /// Buffers owned by the qp, reposted as soon as they are consumed
+    Exclusive(SlabPool),
+    /// Buffers borrowed from a pool shared with other qps
+    Shared {
+        pool: Arc<SharedRecvPool>,
+        /// how many buffers of the pool are posted to this qp now
+        posted: AtomicUsize,
+    },
 }
 
-impl MrPool {
-    fn new(context: Arc<Context>, num: u8) -> Result<Self, Error> {
-        let mut mrs = BTreeMap::new();
-        for i in 0..num {
-            let mr = Arc::new(
-                MemoryRegion::new(Arc::clone(&context), BUF_SIZE as usize)
-                    .map_err(|err| Error::Internal(format!("failed to allocate MR, {err}")))?,
-            );
-            mrs.insert(i as u64, MemoryRegionWrapper { mr, used: false });
+/// The recv side of a qp, shared with the [`RecvBuf`]s that borrow its buffers
+pub(crate) struct RecvRing {
+    qp: Arc<QueuePair>,
+    buffers: RecvBuffers,
+    /// how many buffers are held by received packets
+    lent: AtomicUsize,
+    /// packets received while this many buffers are lent are copied out of theirs
+    low_watermark: usize,
+}
+
+impl RecvRing {
+    /// Create `num` recv buffers of `slot_size` owned by `qp` and post them all
+    pub(crate) fn new_exclusive(
+        qp: Arc<QueuePair>,
+        context: Arc<Context>,
+        num: usize,
+        slot_size: u64,
+        huge_pages: bool,
+    ) -> Result<Arc<Self>, Error> {
+        let mut recv_mrs = SlabPool::new(context, num, slot_size, huge_pages)?;
+
+        // init post recv
+        for _ in 0..num {
+            let slot = recv_mrs
+                .get_free_slot()
+                .ok_or_else(|| Error::Internal("no available slot".to_string()))?;
+            qp.post_recv(slot.mr(), slot.full_range(), slot.id)
+                .map_err(|err| Error::Internal(alloc::format!("internal error: {err}")))?;
         }
-        Ok(Self { mrs })
+
+        Ok(Arc::new(Self {
+            qp,
+            buffers: RecvBuffers::Exclusive(recv_mrs),
+            lent: AtomicUsize::new(0),
+            low_watermark: num / 2,
+        }))
     }
 
-    fn get_free_mr(&mut self) -> Option<(u64, Arc<MemoryRegion>)> {
-        self.mrs.iter_mut().find_map(|(id, mr)| {
-            (!mr.used).then(|| {
-                mr.used = true;
-                (*id, Arc::clone(&mr.mr))
-            })
+    /// Create the recv side of `qp`, which has `posted` buffers borrowed from `pool` posted to it
+    fn new_shared(qp: Arc<QueuePair>, pool: Arc<SharedRecvPool>, posted: AtomicUsize) -> Arc<Self> {
+        Arc::new(Self {
+            qp,
+            low_watermark: pool.config().posted_per_qp / 2,
+            buffers: RecvBuffers::Shared { pool, posted },
+            lent: AtomicUsize::new(0),
         })
     }
 
-    fn mark_mr_free(&mut self, id: u64) -> Result<(), Error> {
-        match self.mrs.get_mut(&id) {
-            Some(mr) => {
-                mr.used = false;
-                Ok(())
-            }
-            None => Err(Error::Internal(format!("mr of id {id} doesn't exist"))),
+    /// A received packet holds a buffer until it's dropped or detached
+    pub(crate) fn lend(&self) {
+        self.lent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether received packets hold so many buffers that the qp may run out of them
+    ///
+    /// Sessions sharing a qp each hold the buffers of the message they receive, however many
+    /// sessions there are, new packets are copied out once half of the buffers are held.
+    pub(crate) fn is_low(&self) -> bool {
+        if self.lent.load(Ordering::Relaxed) >= self.low_watermark {
+            return true;
+        }
+        match &self.buffers {
+            RecvBuffers::Exclusive(_) => false,
+            // the other qps of the pool hold its buffers as well
+            RecvBuffers::Shared { pool, .. } => pool.free() < pool.config().refill_threshold,
+        }
+    }
+
+    pub(crate) fn get_slot(&self, id: u64) -> Result<Slot, Error> {
+        match &self.buffers {
+            RecvBuffers::Exclusive(mrs) => mrs.get_slot(id),
+            RecvBuffers::Shared { pool, .. } => pool.get_slot(id),
         }
     }
 
-    fn get_mr_with_id(&self, id: u64) -> Result<Arc<MemoryRegion>, Error> {
-        match self.mrs.get(&id) {
-            Some(mr) => Ok(Arc::clone(&mr.mr)),
-            None => Err(Error::Internal(format!("mr of id {id} doesn't exist"))),
+    /// Post a consumed buffer to the qp again, or give it back to the shared pool
+    pub(crate) fn repost(&self, slot: &Slot) -> Result<(), Error> {
+        self.lent.fetch_sub(1, Ordering::Relaxed);
+        match &self.buffers {
+            RecvBuffers::Exclusive(_) => self
+                .qp
+                .post_recv(slot.mr(), slot.full_range(), slot.id)
+                .map_err(|err| Error::Internal(format!("failed to post recv, {err}"))),
+            RecvBuffers::Shared { pool, .. } => pool.release(slot.id),
         }
     }
 }
 
-pub struct Transport {
+/// A UD QP together with the slots used to send and receive packets on it
+pub(crate) struct UdQueuePair {
     qp: Arc<QueuePair>,
-    endpoint: DatagramEndpoint,
-    send_mrs: MrPool,
-    recv_mrs: MrPool,
+    /// active mtu of the local port, every slot is as large as it
+    mtu: u64,
+    send_mrs: Arc<Mutex<SlabPool>>,
+    recv: Arc<RecvRing>,
+    events: Option<CqEvents>,
 }
 
-impl Transport {
-    /// Connect to the server
-    pub fn new(context: Arc<Context>, qp_info: QPInfo, port: u8) -> Result<Self, Error> {
-        // create a qp and the remote endpoint
-        let qp = QueuePairBuilder::new(&context)
-            .build_ud()
-            .map_err(|err| Error::Internal(format!("failed to build ud, {err}")))?
-            .bring_up_ud()
-            .map_err(|err| Error::Internal(format!("failed to bring up ud, {err}")))?;
-
-        info!("QP num: {:?}, qkey: {:?}", qp.qp_num(), qp.qkey());
-        let endpoint = DatagramEndpoint::new(
-            &context,
-            port,
-            qp_info.lid,
-            qp_info.gid.into(),
-            qp_info.qp_num,
-            qp_info.qkey,
-        )
-        .map_err(|_| Error::Internal("UD endpoint creation fails".to_string()))?;
-
-        // create mr
-        let send_mrs = MrPool::new(Arc::clone(&context), POOL_SIZE)?;
-        let mut recv_mrs = MrPool::new(context, POOL_SIZE)?;
-
-        // init post recv
-        for _ in 0..POOL_SIZE {
-            let (id, mr) = recv_mrs
-                .get_free_mr()
-                .ok_or_else(|| Error::Internal("no available mr".to_string()))?;
-            qp.post_recv(&mr, 0..BUF_SIZE, id)
-                .map_err(|err| Error::Internal(alloc::format!("internal error: {err}")))?;
-        }
+impl UdQueuePair {
+    /// Create the slot pools of `config` and post all recv buffers to the qp
+    pub(crate) fn new(
+        qp: Arc<QueuePair>,
+        context: Arc<Context>,
+        mtu: u64,
+        config: &QpConfig,
+    ) -> Result<Self, Error> {
+        // create slots
+        let send_mrs = Arc::new(Mutex::new(SlabPool::new(
+            Arc::clone(&context),
+            config.pool_size,
+            mtu,
+            config.huge_pages,
+        )?));
+        let recv = RecvRing::new_exclusive(
+            Arc::clone(&qp),
+            context,
+            config.pool_size,
+            mtu,
+            config.huge_pages,
+        )?;
 
         Ok(Self {
+            events: CqEvents::of_recv_cq(&qp),
             qp,
-            endpoint,
+            mtu,
             send_mrs,
-            recv_mrs,
+            recv,
         })
     }
 
-    pub fn new_with_qp(
+    /// Create the send slot pool of `config`, and post recv buffers borrowed from the shared `pool` to the qp
+    pub(crate) fn new_with_recv_pool(
         qp: Arc<QueuePair>,
         context: Arc<Context>,
-        qp_info: QPInfo,
-        port: u8,
+        mtu: u64,
+        config: &QpConfig,
+        pool: Arc<SharedRecvPool>,
     ) -> Result<Self, Error> {
-        let endpoint = DatagramEndpoint::new(
-            &context,
-            port,
-            qp_info.lid,
-            qp_info.gid.into(),
-            qp_info.qp_num,
-            qp_info.qkey,
-        )
-        .map_err(|_| Error::Internal("UD endpoint creation fails".to_string()))?;
-
-        // create mr
-        let send_mrs = MrPool::new(Arc::clone(&context), POOL_SIZE)?;
-        let mut recv_mrs = MrPool::new(context, POOL_SIZE)?;
-
-        // init post recv
-        for _ in 0..POOL_SIZE {
-            let (id, mr) = recv_mrs
-                .get_free_mr()
-                .ok_or_else(|| Error::Internal("no available mr".to_string()))?;
-            qp.post_recv(&mr, 0..BUF_SIZE, id)
-                .map_err(|err| Error::Internal(alloc::format!("internal error: {err}")))?;
+        if pool.slot_size() < mtu {
+            return Err(Error::Internal(format!(
+                "slots of the shared recv pool are smaller than the mtu {mtu}"
+            )));
         }
+        let send_mrs = Arc::new(Mutex::new(SlabPool::new(
+            context,
+            config.pool_size,
+            mtu,
+            config.huge_pages,
+        )?));
+        let posted = AtomicUsize::new(0);
+        pool.refill(&qp, &posted)?;
 
         Ok(Self {
+            recv: RecvRing::new_shared(Arc::clone(&qp), pool, posted),
+            events: CqEvents::of_recv_cq(&qp),
             qp,
-            endpoint,
+            mtu,
             send_mrs,
-            recv_mrs,
         })
     }
 
-    pub(crate) fn send(&mut self, packets: &[Packet]) -> Result<usize, Error> {
-        // poll send cq and update the mr status
-        let mut wcs = [Default::default(); POOL_SIZE as usize];
-        let res = self
-            .qp
-            .poll_send_cq(&mut wcs)
-            .map_err(|err| Error::Internal(format!("failed to poll send cq, {err}")))?;
-        for wc in res {
-            self.send_mrs.mark_mr_free(wc.wr_id)?;
-        }
+    fn reap_send_cq(&self) -> Result<(), Error> {
+        reap_send_cq(&self.qp, &self.send_mrs, None)
+    }
+
+    fn post_slot(
+        &self,
+        endpoint: &DatagramEndpoint,
+        slot: &OwnedSlot,
+        size: u64,
+    ) -> Result<(), Error> {
+        slot.mark_posted();
+        let slot = slot.slot();
+        self.qp
+            .post_datagram(endpoint, slot.mr(), slot.range(size), slot.id, true)
+            .map_err(|err| {
+                error!("failed to post datagram: {err}");
+                Error::Internal(err.to_string())
+            })
+    }
+
+    /// Post packets to `endpoint` on a path of `path_mtu`,
+    /// returns how many packets haven't been sent because the pool is exhausted
+    pub(crate) fn send(
+        &mut self,
+        endpoint: &DatagramEndpoint,
+        path_mtu: u64,
+        packets: &[Packet],
+    ) -> Result<usize, Error> {
+        self.reap_send_cq()?;
 
         for (i, packet) in packets.iter().enumerate() {
-            if let Some((id, mr)) = self.send_mrs.get_free_mr() {
+            if let Some(slot) = OwnedSlot::alloc(&self.send_mrs) {
                 // serialize arg
-                let buffer: &mut [u8] = unsafe {
-                    alloc::slice::from_raw_parts_mut(mr.get_virt_addr() as _, BUF_SIZE as usize)
-                };
+                let buffer: &mut [u8] = unsafe { slot.slot().as_mut_slice() };
                 let size = bincode::serialized_size(packet)?;
-                assert!((size as usize) <= MAX_PACKET_BYTES);
+                assert!((size as usize) <= max_packet_bytes(path_mtu));
                 bincode::serialize_into(buffer, packet)?;
 
-                // post send
+                // post send, the slot is freed once the send completes
                 debug!("send 1 packet, size: {size}");
-                self.qp
-                    .post_datagram(&self.endpoint, &mr, 0..size, id, true)
-                    .map_err(|err| {
-                        error!("failed to post datagram: {err}");
-                        Error::Internal(err.to_string())
-                    })?;
+                self.post_slot(endpoint, &slot, size)?;
             } else {
                 return Ok(packets.len() - i); // such number of packets haven't been sent
             }
@@ -189,44 +298,40 @@ impl Transport {
         Ok(0) // 0 means all packets have been sent (added to the SQ)
     }
 
-    pub(crate) fn recv(&self) -> Result<Vec<Packet>, Error> {
-        // poll recv cq
-        let mut wcs = [Default::default(); POOL_SIZE as usize];
-        let res = loop {
-            let res = self
-                .qp
-                .poll_recv_cq(&mut wcs)
-                .map_err(|err| Error::Internal(format!("failed to poll cq, {err}")))?;
-            if !res.is_empty() {
-                break res;
-            }
-        };
+    /// Take a free send slot, returns `None` if the send pool is exhausted
+    ///
+    /// The slots kept for acks are left free, they are only taken by `send`.
+    pub(crate) fn alloc_send_slot(&self) -> Result<Option<OwnedSlot>, Error> {
+        self.reap_send_cq()?;
+        Ok(OwnedSlot::alloc_leaving(&self.send_mrs, ACK_SEND_SLOTS))
+    }
 
-        let mut packets = Vec::new();
-        for wc in res {
-            // deserialize arg
-            let mr = self.recv_mrs.get_mr_with_id(wc.wr_id)?;
-            let msg_sz = wc.byte_len as usize - UD_DATA_OFFSET;
-            let msg: Packet = bincode::deserialize(unsafe {
-                alloc::slice::from_raw_parts(
-                    (mr.get_virt_addr() as usize + UD_DATA_OFFSET) as *mut u8,
-                    msg_sz,
-                )
-            })?; // TODO: post recv when handle error
-            packets.push(msg);
-
-            // post recv
-            self.qp
-                .post_recv(&mr, 0..BUF_SIZE, wc.wr_id)
-                .map_err(|err| Error::Internal(alloc::format!("internal error: {err}")))?;
+    /// Post packets already serialized in send slots to `endpoint` on a path of `path_mtu`
+    ///
+    /// A packet whose previous post is still in the send queue is skipped, posting it again is useless.
+    pub(crate) fn post_bufs(
+        &self,
+        endpoint: &DatagramEndpoint,
+        path_mtu: u64,
+        bufs: &[&PacketBuf],
+    ) -> Result<(), Error> {
+        self.reap_send_cq()?;
+
+        for buf in bufs {
+            assert!((buf.len() as usize) <= max_packet_bytes(path_mtu));
+            if buf.slot().is_posted() {
+                continue;
+            }
+            debug!("post packet {}, size: {}", buf.seq(), buf.len());
+            self.post_slot(endpoint, buf.slot(), buf.len())?;
         }
-
-        debug!("recv {} packets", packets.len());
-
-        Ok(packets)
+        Ok(())
     }
 
-    pub(crate) fn try_recv(&self) -> Result<Vec<Packet>, Error> {
+    /// Poll the recv cq once and return the packets received, possibly none
+    ///
+    /// The packets are left in their recv buffers, a buffer is reposted when its packet is dropped.
+    pub(crate) fn poll_recv(&self) -> Result<Vec<RecvBuf>, Error> {
         // poll recv cq
         let mut wcs = [Default::default(); POOL_SIZE as usize];
         let res = self
@@ -236,21 +341,22 @@ impl Transport {
 
         let mut packets = Vec::new();
         for wc in res {
-            // deserialize arg
-            let mr = self.recv_mrs.get_mr_with_id(wc.wr_id)?;
+            let slot = self.recv.get_slot(wc.wr_id)?;
+            if let RecvBuffers::Shared { posted, .. } = &self.recv.buffers {
+                posted.fetch_sub(1, Ordering::Relaxed);
+            }
+
+            // parse the header in place, a malformed packet reposts its buffer when dropped
             let msg_sz = wc.byte_len as usize - UD_DATA_OFFSET;
-            let msg: Packet = bincode::deserialize(unsafe {
-                alloc::slice::from_raw_parts(
-                    (mr.get_virt_addr() as usize + UD_DATA_OFFSET) as *mut u8,
-                    msg_sz,
-                )
-            })?; // TODO: post recv when handle error
-            packets.push(msg);
-
-            // post recv
-            self.qp
-                .post_recv(&mr, 0..BUF_SIZE, wc.wr_id)
-                .map_err(|err| Error::Internal(alloc::format!("internal error: {err}")))?;
+            match RecvBuf::parse(slot, UD_DATA_OFFSET, msg_sz, Arc::clone(&self.recv)) {
+                Ok(packet) => packets.push(packet),
+                Err(err) => warn!("drop malformed packet, {err}"),
+            }
+        }
+
+        // top up the qp if it is running out of recv buffers
+        if let RecvBuffers::Shared { pool, posted } = &self.recv.buffers {
+            pool.refill(&self.qp, posted)?;
         }
 
         if !packets.is_empty() {
@@ -260,25 +366,305 @@ impl Transport {
         Ok(packets)
     }
 
+    pub(crate) fn send_pool_stats(&self) -> PoolStats {
+        self.send_mrs.lock().stats()
+    }
+
+    pub(crate) fn set_send_pool_limit(&mut self, limit: usize) {
+        self.send_mrs.lock().set_limit(limit)
+    }
+
+    pub(crate) fn mtu(&self) -> u64 {
+        self.mtu
+    }
+
+    pub(crate) fn events(&self) -> Option<&CqEvents> {
+        self.events.as_ref()
+    }
+
+    pub(crate) fn qp_info(&self) -> QPInfo {
+        QPInfo {
+            lid: self.qp.lid().unwrap(),
+            gid: services_user::ibv_gid_wrapper::from(self.qp.gid().unwrap()),
+            qp_num: self.qp.qp_num(),
+            qkey: self.qp.qkey(),
+            mtu: self.mtu,
+        }
+    }
+}
+
+/// Poll the send cq of `qp` and update the status of the slots in `send_mrs`
+///
+/// Reads of a rendezvous channel sharing the qp complete on the same cq, they're left in `reads`.
+pub(crate) fn reap_send_cq(
+    qp: &QueuePair,
+    send_mrs: &Mutex<SlabPool>,
+    reads: Option<&ReadCompletions>,
+) -> Result<(), Error> {
+    let mut wcs = [Default::default(); POOL_SIZE as usize];
+    let res = qp
+        .poll_send_cq(&mut wcs)
+        .map_err(|err| Error::Internal(format!("failed to poll send cq, {err}")))?;
+    if !res.is_empty() {
+        let mut send_mrs = send_mrs.lock();
+        for wc in res {
+            if wc.wr_id & READ_WR_ID == 0 {
+                send_mrs.mark_slot_completed(wc.wr_id)?;
+                continue;
+            }
+            // a read of the rendezvous channel sharing the qp, left for it to handle
+            match reads {
+                Some(reads) => reads.lock().push((wc.wr_id, wc.status as u32)),
+                None => {
+                    return Err(Error::Internal(format!(
+                        "unexpected read completion {}",
+                        wc.wr_id
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create an endpoint(address handle) to send datagrams to the remote qp
+pub(crate) fn new_endpoint(
+    context: &Arc<Context>,
+    port: u8,
+    qp_info: QPInfo,
+) -> Result<DatagramEndpoint, Error> {
+    DatagramEndpoint::new(
+        context,
+        port,
+        qp_info.lid,
+        qp_info.gid.into(),
+        qp_info.qp_num,
+        qp_info.qkey,
+    )
+    .map_err(|_| Error::Internal("UD endpoint creation fails".to_string()))
+}
+
+/// Create a UD qp with `config` and bring it up
+///
+/// Its cqs are created on a completion channel, for transports in [`WaitMode::Hybrid`] to block on.
+pub fn new_ud_qp(context: &Arc<Context>, config: &QpConfig) -> Result<Arc<QueuePair>, Error> {
+    let mut builder = QueuePairBuilder::new(context);
+    builder
+        .enable_comp_channel()
+        .set_gid_index(config.gid_index)
+        .set_max_send_wr(config.pool_size as _)
+        .set_max_recv_wr(config.pool_size as _);
+    let qp = builder
+        .build_ud()
+        .map_err(|err| Error::Internal(format!("failed to build ud, {err}")))?
+        .bring_up_ud()
+        .map_err(|err| Error::Internal(format!("failed to bring up ud, {err}")))?;
+    info!("QP num: {:?}, qkey: {:?}", qp.qp_num(), qp.qkey());
+    Ok(qp)
+}
+
+/// Packet-level I/O that a [`Session`](crate::session::Session) runs on top of
+pub trait PacketTransport: Send {
+    /// Post all packets to the send queue, waiting for free buffers if necessary
+    fn send_burst(&mut self, packets: Vec<Packet>) -> Result<(), Error>;
+
+    /// Block until some packets are received
+    ///
+    /// A received packet borrows the recv buffer it landed in until it's dropped.
+    fn recv(&mut self) -> Result<Vec<RecvBuf>, Error> {
+        self.recv_timeout(None)
+    }
+
+    /// Block until some packets are received or `timeout_micros` elapsed, returns no packets on timeout
+    fn recv_timeout(&mut self, timeout_micros: Option<u64>) -> Result<Vec<RecvBuf>, Error>;
+
+    /// Return the packets received so far without blocking, possibly none
+    fn try_recv(&mut self) -> Result<Vec<RecvBuf>, Error>;
+
+    /// How many bytes of user data fit in one packet
+    fn max_data_bytes(&self) -> usize;
+
+    /// Take a send slot to serialize a packet into, returns `None` if all are in use
+    ///
+    /// The first `PACKET_HEADER_BYTES` of the slot are reserved for the packet header.
+    /// The last `ACK_SEND_SLOTS` free slots are left for `send_burst`.
+    fn try_alloc_send_slot(&mut self) -> Result<Option<OwnedSlot>, Error>;
+
+    /// Take a send slot, waiting for one if all are in use
+    ///
+    /// Slots held by unacknowledged packets are only freed by the one who holds them,
+    /// a sender waiting for its own acks should poll them between `try_alloc_send_slot` instead.
+    fn alloc_send_slot(&mut self) -> Result<OwnedSlot, Error> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(slot) = self.try_alloc_send_slot()? {
+                break Ok(slot);
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Post packets serialized in send slots, the same packets can be posted again for retransmission
+    fn post_bufs(&mut self, bufs: &[&PacketBuf]) -> Result<(), Error>;
+
+    /// Whether packets are delivered reliably and in order, so that sessions needn't ack them
+    fn is_reliable(&self) -> bool {
+        false
+    }
+
+    /// Completion events to block on while waiting for packets, if the recv cq has any
+    fn cq_events(&self) -> Option<CqEvents> {
+        None
+    }
+}
+
+/// A transport that exclusively owns a UD qp and talks to exactly one remote qp
+pub struct Transport {
+    endpoint: DatagramEndpoint,
+    ud: UdQueuePair,
+    /// the smaller one of the local and the remote mtu
+    path_mtu: u64,
+    wait_mode: WaitMode,
+}
+
+impl Transport {
+    /// Connect to the server
+    pub fn new(context: Arc<Context>, qp_info: QPInfo, port: u8) -> Result<Self, Error> {
+        // create a qp and the remote endpoint
+        let qp = new_ud_qp(&context, &QpConfig::default())?;
+        Self::new_with_qp(qp, context, qp_info, port)
+    }
+
+    pub fn new_with_qp(
+        qp: Arc<QueuePair>,
+        context: Arc<Context>,
+        qp_info: QPInfo,
+        port: u8,
+    ) -> Result<Self, Error> {
+        Self::new_with_qp_config(qp, context, qp_info, port, &QpConfig::default())
+    }
+
+    /// Like `new_with_qp`, with the slot pools sized by `config`, which `qp` was created with
+    pub fn new_with_qp_config(
+        qp: Arc<QueuePair>,
+        context: Arc<Context>,
+        qp_info: QPInfo,
+        port: u8,
+        config: &QpConfig,
+    ) -> Result<Self, Error> {
+        let mtu = query_mtu(&context, port)?;
+        let path_mtu = mtu.min(qp_info.mtu);
+        info!(
+            "path mtu: {path_mtu}, local: {mtu}, remote: {}",
+            qp_info.mtu
+        );
+
+        let endpoint = new_endpoint(&context, port, qp_info)?;
+        let ud = UdQueuePair::new(qp, context, mtu, config)?;
+
+        Ok(Self {
+            endpoint,
+            ud,
+            path_mtu,
+            wait_mode: WaitMode::default(),
+        })
+    }
+
+    pub(crate) fn send(&mut self, packets: &[Packet]) -> Result<usize, Error> {
+        self.ud.send(&self.endpoint, self.path_mtu, packets)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn recv(&self) -> Result<Vec<RecvBuf>, Error> {
+        Ok(self.recv_timeout(None)?.unwrap_or_default())
+    }
+
+    fn recv_timeout(&self, timeout_micros: Option<u64>) -> Result<Option<Vec<RecvBuf>>, Error> {
+        poll_until(self.wait_mode, self.ud.events(), timeout_micros, || {
+            let packets = self.ud.poll_recv()?;
+            Ok((!packets.is_empty()).then_some(packets))
+        })
+    }
+
+    pub(crate) fn try_recv(&self) -> Result<Vec<RecvBuf>, Error> {
+        self.ud.poll_recv()
+    }
+
     pub(crate) fn send_burst(&mut self, packets: Vec<Packet>) -> Result<(), Error> {
         let len = packets.len();
         let mut left_to_be_sent: usize = len;
+        let mut backoff = Backoff::new();
 
         loop {
-            left_to_be_sent = self.send(&packets[len - left_to_be_sent..])?;
-            if left_to_be_sent == 0 {
+            let left = self.send(&packets[len - left_to_be_sent..])?;
+            if left == 0 {
                 break Ok(());
             }
+
+            // the send pool is exhausted, wait for send completions to free some slots
+            if left < left_to_be_sent {
+                backoff.reset();
+            } else {
+                backoff.snooze();
+            }
+            left_to_be_sent = left;
         }
     }
 
     pub fn qp_info(&self) -> QPInfo {
-        QPInfo {
-            lid: self.qp.lid().unwrap(),
-            gid: services_user::ibv_gid_wrapper::from(self.qp.gid().unwrap()),
-            qp_num: self.qp.qp_num(),
-            qkey: self.qp.qkey(),
-        }
+        self.ud.qp_info()
+    }
+
+    /// The mtu negotiated with the remote end
+    pub fn path_mtu(&self) -> u64 {
+        self.path_mtu
+    }
+
+    pub fn max_data_bytes(&self) -> usize {
+        max_data_bytes(self.path_mtu)
+    }
+
+    pub fn send_pool_stats(&self) -> PoolStats {
+        self.ud.send_pool_stats()
+    }
+
+    /// Allow the send pool to grow up to `limit` slots when it's exhausted
+    pub fn set_send_pool_limit(&mut self, limit: usize) {
+        self.ud.set_send_pool_limit(limit)
+    }
+
+    pub fn set_wait_mode(&mut self, mode: WaitMode) {
+        self.wait_mode = mode;
+    }
+}
+
+impl PacketTransport for Transport {
+    fn send_burst(&mut self, packets: Vec<Packet>) -> Result<(), Error> {
+        Transport::send_burst(self, packets)
+    }
+
+    fn recv_timeout(&mut self, timeout_micros: Option<u64>) -> Result<Vec<RecvBuf>, Error> {
+        Ok(Transport::recv_timeout(self, timeout_micros)?.unwrap_or_default())
+    }
+
+    fn try_recv(&mut self) -> Result<Vec<RecvBuf>, Error> {
+        Transport::try_recv(self)
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        Transport::max_data_bytes(self)
+    }
+
+    fn try_alloc_send_slot(&mut self) -> Result<Option<OwnedSlot>, Error> {
+        self.ud.alloc_send_slot()
+    }
+
+    fn post_bufs(&mut self, bufs: &[&PacketBuf]) -> Result<(), Error> {
+        self.ud.post_bufs(&self.endpoint, self.path_mtu, bufs)
+    }
+
+    fn cq_events(&self) -> Option<CqEvents> {
+        self.ud.events().cloned()
     }
 }
 
@@ -286,20 +672,21 @@ impl Transport {
 mod tests {
     use alloc::vec;
 
+    use super::{new_ud_qp, QpConfig, DEFAULT_POOL_SIZE};
     use crate::{
         messages::Packet,
-        transport::MAX_DATA_BYTES,
         utils::{
-            sleep_millis,
-            tests::{new_random_data, new_two_transport},
+            now_micros, sleep_millis,
+            tests::{new_random_data, new_test_context, new_two_transport, new_two_transport_on},
         },
+        wait::WaitMode,
     };
 
     #[test]
     fn it_works() {
         let (mut tp1, tp2) = new_two_transport();
 
-        let data = new_random_data(MAX_DATA_BYTES);
+        let data = new_random_data(tp1.max_data_bytes());
         let packet = Packet::new(0, 0, data.clone());
         tp1.send_burst(vec![packet]).unwrap();
 
@@ -321,6 +708,53 @@ mod tests {
         assert_eq!(packet.into_data(), received_packet.remove(0).into_data());
     }
 
+    #[test]
+    // packets received while half of the recv buffers are held are copied out of theirs
+    fn recv_ring_runs_low() {
+        let (mut tp1, tp2) = new_two_transport();
+
+        let packets = (0..DEFAULT_POOL_SIZE as u64)
+            .map(|seq| Packet::new(seq, 0, new_random_data(64)))
+            .collect();
+        tp1.send_burst(packets).unwrap();
+        let mut received = vec![];
+        while received.len() < DEFAULT_POOL_SIZE {
+            received.extend(tp2.recv().unwrap());
+        }
+
+        let borrowed = received
+            .iter()
+            .filter(|packet| packet.is_borrowed())
+            .count();
+        assert_eq!(borrowed, DEFAULT_POOL_SIZE / 2);
+    }
+
+    #[test]
+    fn hybrid_wait_wakes_on_completion() {
+        let context = new_test_context();
+        let qp1 = new_ud_qp(&context, &QpConfig::default()).unwrap();
+        let qp2 = new_ud_qp(&context, &QpConfig::default()).unwrap();
+        let (mut tp1, mut tp2) = new_two_transport_on(context, qp1, qp2);
+        // blocks on the completion channel at once, and for much longer than the send takes
+        tp2.set_wait_mode(WaitMode::Hybrid {
+            spin_budget: 0,
+            max_park_micros: 10_000_000,
+        });
+
+        let packet = Packet::new(0, 0, new_random_data(64));
+        let data = packet.clone().into_data();
+        let start = now_micros();
+        let handle = std::thread::spawn(move || {
+            sleep_millis(100);
+            tp1.send_burst(vec![packet]).unwrap();
+        });
+
+        let mut received_packet = tp2.recv().unwrap();
+        assert_eq!(data, received_packet.remove(0).into_data());
+        assert!(now_micros() - start < 5_000_000);
+        handle.join().unwrap();
+    }
+
     #[test]
     fn try_recv_not_block() {
         let (mut tp1, tp2) = new_two_transport();
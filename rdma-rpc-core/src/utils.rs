@@ -1,9 +1,61 @@
+#[cfg(test)]
 pub(crate) fn sleep_millis(duration: u32) {
     unsafe {
         libc::usleep(1000 * duration);
     }
 }
 
+pub(crate) fn sleep_micros(duration: u32) {
+    unsafe {
+        libc::usleep(duration);
+    }
+}
+
+/// Microseconds elapsed since an arbitrary point in the past, never goes backwards
+pub(crate) fn now_micros() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+}
+
+/// Exponential backoff for waiting on a resource that is temporarily exhausted
+///
+/// The first few rounds spin on the cpu, later rounds sleep for exponentially longer, up to 1ms.
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    const SPIN_LIMIT: u32 = 6;
+    const MAX_SLEEP_MICROS: u32 = 1000;
+
+    pub(crate) fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Start over after the resource became available again
+    pub(crate) fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    pub(crate) fn snooze(&mut self) {
+        if self.step <= Self::SPIN_LIMIT {
+            for _ in 0..(1 << self.step) {
+                core::hint::spin_loop();
+            }
+        } else {
+            let exp = (self.step - Self::SPIN_LIMIT).min(10);
+            sleep_micros((1 << exp).min(Self::MAX_SLEEP_MICROS));
+        }
+        self.step = self.step.saturating_add(1);
+    }
+}
+
 /// Utils for tests
 #[cfg(test)]
 pub(crate) mod tests {
@@ -13,11 +65,14 @@ pub(crate) mod tests {
     use libc::time_t;
     use KRdmaKit::{context::Context, random, services_user, QueuePair, QueuePairBuilder, UDriver};
 
-    use crate::{messages::QPInfo, transport::Transport};
+    use crate::{
+        messages::QPInfo,
+        transport::{query_mtu, Transport},
+    };
 
     pub(crate) fn new_test_context() -> Arc<Context> {
         let udriver = UDriver::create().unwrap();
-        let device = udriver.devices().get(0).unwrap();
+        let device = udriver.devices().first().unwrap();
         device.open_context().unwrap()
     }
 
@@ -31,19 +86,29 @@ pub(crate) mod tests {
 
     pub(crate) fn new_two_transport() -> (Transport, Transport) {
         let context = new_test_context();
-        let qp1 = new_test_qp(&context);
+        let (qp1, qp2) = (new_test_qp(&context), new_test_qp(&context));
+        new_two_transport_on(context, qp1, qp2)
+    }
+
+    /// Two transports on `qp1` and `qp2` talking to each other
+    pub(crate) fn new_two_transport_on(
+        context: Arc<Context>,
+        qp1: Arc<QueuePair>,
+        qp2: Arc<QueuePair>,
+    ) -> (Transport, Transport) {
         let qp_info1 = QPInfo {
             lid: qp1.lid().unwrap(),
             gid: services_user::ibv_gid_wrapper::from(qp1.gid().unwrap()),
             qp_num: qp1.qp_num(),
             qkey: qp1.qkey(),
+            mtu: query_mtu(&context, 1).unwrap(),
         };
-        let qp2 = new_test_qp(&context);
         let qp_info2 = QPInfo {
             lid: qp2.lid().unwrap(),
             gid: services_user::ibv_gid_wrapper::from(qp2.gid().unwrap()),
             qp_num: qp2.qp_num(),
             qkey: qp2.qkey(),
+            mtu: query_mtu(&context, 1).unwrap(),
         };
 
         let tp1 = Transport::new_with_qp(qp1, Arc::clone(&context), qp_info2, 1).unwrap();
@@ -0,0 +1,225 @@
+use alloc::{format, sync::Arc};
+use core::ptr;
+
+use KRdmaKit::{
+    rdma_shim::bindings::{ibv_ack_cq_events, ibv_comp_channel, ibv_cq, ibv_get_cq_event},
+    QueuePair,
+};
+
+use crate::{
+    error::Error,
+    utils::{now_micros, sleep_micros},
+};
+
+/// How a transport waits for completions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WaitMode {
+    /// Poll the cq in a tight loop, lowest latency but keeps a core busy even when idle
+    #[default]
+    BusyPoll,
+    /// Poll the cq `spin_budget` times, then arm the cq and block on its completion channel
+    /// for at most `max_park_micros` at a time, so idle transports barely use the cpu
+    ///
+    /// A transport whose cq has no completion channel, e.g. one built on a qp the caller created,
+    /// parks with exponentially growing sleeps of at most `max_park_micros` instead.
+    Hybrid {
+        spin_budget: u32,
+        max_park_micros: u32,
+    },
+}
+
+/// The completion events of the recv cq of a qp, a waiter blocks on them instead of polling
+#[derive(Clone)]
+pub struct CqEvents {
+    /// keeps the cq and its channel alive
+    qp: Arc<QueuePair>,
+}
+
+impl CqEvents {
+    /// The events of the recv cq of `qp`, if the cq was created on a completion channel
+    pub(crate) fn of_recv_cq(qp: &Arc<QueuePair>) -> Option<Self> {
+        let events = Self { qp: Arc::clone(qp) };
+        let channel = events.channel();
+        if channel.is_null() {
+            return None;
+        }
+        // several waiters may share the channel, the one that loses the race for an event mustn't block
+        unsafe {
+            let fd = (*channel).fd;
+            libc::fcntl(
+                fd,
+                libc::F_SETFL,
+                libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK,
+            );
+        }
+        Some(events)
+    }
+
+    fn cq(&self) -> *mut ibv_cq {
+        self.qp.recv_cq().raw_ptr().as_ptr()
+    }
+
+    fn channel(&self) -> *mut ibv_comp_channel {
+        unsafe { (*self.cq()).channel }
+    }
+
+    /// Ask for an event on the next completion of the cq
+    fn arm(&self) -> Result<(), Error> {
+        let cq = self.cq();
+        // ibv_req_notify_cq is inlined by verbs.h, so call through the ops of the context as it does
+        let res = unsafe {
+            match (*(*cq).context).ops.req_notify_cq {
+                Some(req_notify_cq) => req_notify_cq(cq, 0),
+                None => -1,
+            }
+        };
+        if res != 0 {
+            return Err(Error::Internal(format!("failed to arm cq, {res}")));
+        }
+        Ok(())
+    }
+
+    /// Block until an event arrives or `timeout_micros` elapsed, returns whether an event arrived
+    fn wait(&self, timeout_micros: u64) -> bool {
+        let channel = self.channel();
+        let mut pollfd = libc::pollfd {
+            fd: unsafe { (*channel).fd },
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_millis = timeout_micros.div_ceil(1000).min(i32::MAX as u64) as i32;
+        // an interrupted poll is taken as a timeout, the caller polls the cq anyway
+        if unsafe { libc::poll(&mut pollfd, 1, timeout_millis) } <= 0 {
+            return false;
+        }
+
+        // another waiter on the channel may have taken the event first
+        let mut cq = ptr::null_mut();
+        let mut cq_context = ptr::null_mut();
+        if unsafe { ibv_get_cq_event(channel, &mut cq, &mut cq_context) } != 0 {
+            return false;
+        }
+        unsafe { ibv_ack_cq_events(cq, 1) };
+        true
+    }
+}
+
+/// Tracks how long a transport has been waiting, and decides whether to spin or park
+pub(crate) struct Waiter<'a> {
+    mode: WaitMode,
+    events: Option<&'a CqEvents>,
+    polls: u32,
+    park_step: u32,
+    /// whether the cq asks for an event on its next completion
+    armed: bool,
+}
+
+impl<'a> Waiter<'a> {
+    pub(crate) fn new(mode: WaitMode, events: Option<&'a CqEvents>) -> Self {
+        Self {
+            mode,
+            events,
+            polls: 0,
+            park_step: 0,
+            armed: false,
+        }
+    }
+
+    /// Called after a poll found nothing, waits at most `max_micros` before the next poll
+    pub(crate) fn idle(&mut self, max_micros: u64) -> Result<(), Error> {
+        let (spin_budget, max_park_micros) = match self.mode {
+            WaitMode::BusyPoll => {
+                core::hint::spin_loop();
+                return Ok(());
+            }
+            WaitMode::Hybrid {
+                spin_budget,
+                max_park_micros,
+            } => (spin_budget, max_park_micros as u64),
+        };
+        if self.polls < spin_budget {
+            self.polls += 1;
+            core::hint::spin_loop();
+            return Ok(());
+        }
+
+        match self.events {
+            // arm first and poll once more, a completion that arrived before arming raises no event
+            Some(events) if !self.armed => {
+                events.arm()?;
+                self.armed = true;
+            }
+            // a wait is bounded, in case another waiter on the channel took the event
+            Some(events) => {
+                if events.wait(max_park_micros.min(max_micros)) {
+                    self.armed = false;
+                }
+            }
+            None => {
+                let park = (1u64 << self.park_step.min(20))
+                    .min(max_park_micros)
+                    .min(max_micros);
+                sleep_micros(park as u32);
+                self.park_step += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Poll with `poll` until it returns something, or until `timeout_micros` elapsed,
+/// blocking on `events` in between if the mode allows
+///
+/// Returns `None` on timeout, and waits forever if `timeout_micros` is `None`.
+pub(crate) fn poll_until<T>(
+    mode: WaitMode,
+    events: Option<&CqEvents>,
+    timeout_micros: Option<u64>,
+    mut poll: impl FnMut() -> Result<Option<T>, Error>,
+) -> Result<Option<T>, Error> {
+    let deadline = timeout_micros.map(|timeout| now_micros() + timeout);
+    let mut waiter = Waiter::new(mode, events);
+    loop {
+        if let Some(res) = poll()? {
+            return Ok(Some(res));
+        }
+
+        let left = match deadline {
+            Some(deadline) => {
+                let now = now_micros();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                deadline - now
+            }
+            None => u64::MAX,
+        };
+        waiter.idle(left)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{poll_until, WaitMode};
+    use crate::utils::now_micros;
+
+    #[test]
+    fn poll_until_timeout() {
+        let mode = WaitMode::Hybrid {
+            spin_budget: 16,
+            max_park_micros: 1000,
+        };
+
+        let start = now_micros();
+        let res = poll_until::<()>(mode, None, Some(10_000), || Ok(None));
+        assert_eq!(res.unwrap(), None);
+        assert!(now_micros() - start >= 10_000);
+
+        let mut n = 0;
+        let res = poll_until(mode, None, None, || {
+            n += 1;
+            Ok((n == 100).then_some(n))
+        });
+        assert_eq!(res.unwrap(), Some(100));
+    }
+}
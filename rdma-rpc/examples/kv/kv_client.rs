@@ -1,4 +1,6 @@
-use rdma_rpc::Client;
+use std::time::Duration;
+
+use rdma_rpc::{ClientBuilder, TransportMode};
 
 mod protocol;
 use protocol::{Args, Resp};
@@ -10,8 +12,29 @@ fn main() {
         .with_env_filter(EnvFilter::from_default_env())
         .with_max_level(Level::DEBUG)
         .init();
-    let mut client: Client<Args, Resp> =
-        Client::new("rxe_0", "127.0.0.1:10001".parse().unwrap(), 1).unwrap();
+    // pass `--rc` to talk to a server started with `--rc`
+    let mode = if std::env::args().any(|arg| arg == "--rc") {
+        TransportMode::Rc
+    } else {
+        TransportMode::Ud
+    };
+    let mut client = ClientBuilder::<Args, Resp>::new("rxe_0", "127.0.0.1:10001".parse().unwrap())
+        .mode(mode)
+        // a get may run twice, so the server doesn't keep its response for retries
+        .idempotent(|args| matches!(args, Args::Get(_) | Args::Scan(..)))
+        .call_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
     info!("call 1 {:?}", client.send(Args::Put(1, 1)).unwrap());
     info!("call 2 {:?}", client.send(Args::Get(1)).unwrap());
+    for k in 2..200 {
+        client.send(Args::Put(k, k * k)).unwrap();
+    }
+    let gets = (0..32).map(Args::Get).collect();
+    for resp in client.call_batch(gets).unwrap() {
+        info!("batched get {:?}", resp.unwrap());
+    }
+    for resp in client.send_streaming(Args::Scan(0, 100)).unwrap() {
+        info!("scan {:?}", resp.unwrap());
+    }
 }
@@ -1,18 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     sync::{Arc, Mutex},
 };
 
 use protocol::{Args, Resp};
-use rdma_rpc::Server;
-use rdma_rpc_core::server_stub::RpcHandler;
+use rdma_rpc::{Server, TransportMode};
+use rdma_rpc_core::{error::Error, server_stub::RpcHandler, streaming::StreamCall};
 use tracing::Level;
 use tracing_subscriber::EnvFilter;
 
 mod protocol;
 
+const SCAN_BATCH: usize = 64; // entries in one streamed response
+
 struct KVRpcHandler {
-    store: Arc<Mutex<HashMap<i32, i32>>>,
+    store: Arc<Mutex<BTreeMap<i32, i32>>>,
 }
 
 impl RpcHandler for KVRpcHandler {
@@ -28,14 +30,37 @@ impl RpcHandler for KVRpcHandler {
                 store.insert(k, v);
                 Self::Resp::Put
             }
+            Args::Scan(start, end) => Resp::Scan(scan(&store, start, end)),
+        }
+    }
+
+    fn handle_stream(&self, call: &mut StreamCall<'_, Resp, Args>) -> Result<(), Error> {
+        while let Some(arg) = call.recv()? {
+            let (start, end) = match arg {
+                Args::Scan(start, end) => (start, end),
+                arg => return Err(Error::Internal(format!("{arg:?} can't be streamed"))),
+            };
+            // don't hold the lock while waiting for the client to take the batches
+            let entries = scan(&self.store.lock().unwrap(), start, end);
+            for batch in entries.chunks(SCAN_BATCH) {
+                call.send(Resp::Scan(batch.to_vec()))?;
+            }
         }
+        Ok(())
+    }
+}
+
+fn scan(store: &BTreeMap<i32, i32>, start: i32, end: i32) -> Vec<(i32, i32)> {
+    if start >= end {
+        return Vec::new();
     }
+    store.range(start..end).map(|(k, v)| (*k, *v)).collect()
 }
 
 impl KVRpcHandler {
     fn new() -> Self {
         Self {
-            store: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 }
@@ -45,11 +70,18 @@ fn main() {
         .with_env_filter(EnvFilter::from_default_env())
         .with_max_level(Level::DEBUG)
         .init();
-    Server::new(
+    // pass `--rc` to serve clients over rc qps
+    let mode = if std::env::args().any(|arg| arg == "--rc") {
+        TransportMode::Rc
+    } else {
+        TransportMode::Ud
+    };
+    Server::new_with_mode(
         "rxe_0",
         1,
         "127.0.0.1:10001".parse().unwrap(),
         Arc::new(KVRpcHandler::new()),
+        mode,
     )
     .unwrap()
     .serve()
@@ -4,10 +4,13 @@ use serde::{Deserialize, Serialize};
 pub enum Args {
     Get(i32),
     Put(i32, i32),
+    /// keys in `[start, end)`, streamed back in batches
+    Scan(i32, i32),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Resp {
     Get(Option<i32>),
     Put,
+    Scan(Vec<(i32, i32)>),
 }
@@ -0,0 +1,493 @@
+//! Builders that set the tunables of a server or a client, checked before any qp is created
+
+use std::{fmt::Debug, marker::PhantomData, net::SocketAddrV4, sync::Arc, time::Duration};
+
+use rdma_rpc_core::{
+    codec::CodecKind,
+    recv_pool::SharedRecvConfig,
+    retry::RetryPolicy,
+    server_stub::RpcHandler,
+    session::{Session, SessionConfig, DEFAULT_POLL_INTERVAL, DEFAULT_RETRANSMIT_TIMEOUT},
+    transport::{QpConfig, ACK_SEND_SLOTS, MAX_MTU, MAX_POOL_SIZE, MIN_MTU, MIN_POOL_SIZE},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+#[cfg(feature = "log")]
+use tracing::Level;
+
+use crate::{
+    handshake::DEFAULT_HANDSHAKE_TIMEOUT, Client, ClientError, Server, ServerError, TransportMode,
+    DEFAULT_SERVER_QPS,
+};
+
+/// The port of the device used unless another one is set
+pub const DEFAULT_IB_PORT: u8 = 1;
+/// Gid tables of IB and RoCE ports have at most this many entries
+const MAX_GID_INDEX: usize = 255;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("{name} is {value}, it must be {expected}")]
+    Invalid {
+        name: &'static str,
+        value: String,
+        expected: String,
+    },
+    #[error("{0}")]
+    Conflict(String),
+}
+
+fn at_least<V: PartialOrd + Debug>(
+    name: &'static str,
+    value: V,
+    min: V,
+) -> Result<(), ConfigError> {
+    if value >= min {
+        return Ok(());
+    }
+    Err(ConfigError::Invalid {
+        name,
+        value: format!("{value:?}"),
+        expected: format!("at least {min:?}"),
+    })
+}
+
+fn at_most<V: PartialOrd + Debug>(name: &'static str, value: V, max: V) -> Result<(), ConfigError> {
+    if value <= max {
+        return Ok(());
+    }
+    Err(ConfigError::Invalid {
+        name,
+        value: format!("{value:?}"),
+        expected: format!("at most {max:?}"),
+    })
+}
+
+/// Log at `level` to stdout, unless the application installed a subscriber already
+#[cfg(feature = "log")]
+fn init_logging(level: Option<Level>) {
+    if let Some(level) = level {
+        let _ = tracing_subscriber::fmt().with_max_level(level).try_init();
+    }
+}
+
+/// The tunables of the sessions of a server or of a client
+#[derive(Debug, Clone)]
+pub(crate) struct SessionSetup {
+    /// codecs the sessions may use, the preferred one first
+    pub(crate) codecs: Vec<CodecKind>,
+    /// proposed by a client, or the limits of what clients propose to a server
+    pub(crate) config: SessionConfig,
+    pub(crate) qp: QpConfig,
+    pub(crate) retransmit_timeout: Duration,
+    pub(crate) poll_interval: Duration,
+    pub(crate) handshake_timeout: Duration,
+    /// messages at least this large are pulled by the remote end, if both ends turn rendezvous on
+    pub(crate) rendezvous: Option<usize>,
+}
+
+impl SessionSetup {
+    fn new(codecs: &[CodecKind]) -> Self {
+        Self {
+            codecs: codecs.to_vec(),
+            config: SessionConfig::default(),
+            qp: QpConfig::default(),
+            retransmit_timeout: Duration::from_micros(DEFAULT_RETRANSMIT_TIMEOUT),
+            poll_interval: Duration::from_micros(DEFAULT_POLL_INTERVAL),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            rendezvous: None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.codecs.is_empty() {
+            return Err(ConfigError::Conflict("no codec to use".to_string()));
+        }
+        at_least("window size", self.config.window_size, 1)?;
+        at_least("mtu", self.config.mtu, MIN_MTU)?;
+        at_most("mtu", self.config.mtu, MAX_MTU)?;
+        at_least("max message bytes", self.config.max_message_bytes, 1)?;
+        at_least("pool size", self.qp.pool_size, MIN_POOL_SIZE)?;
+        at_most("pool size", self.qp.pool_size, MAX_POOL_SIZE)?;
+        at_most("gid index", self.qp.gid_index, MAX_GID_INDEX)?;
+        // every packet of the window holds a send slot until it's acknowledged,
+        // and acks need slots of their own
+        if self.config.window_size + ACK_SEND_SLOTS > self.qp.pool_size {
+            return Err(ConfigError::Conflict(format!(
+                "window size {} leaves no send slots for acks in a pool of {}",
+                self.config.window_size, self.qp.pool_size
+            )));
+        }
+        at_least(
+            "poll interval",
+            self.poll_interval,
+            Duration::from_micros(1),
+        )?;
+        at_least(
+            "retransmit timeout",
+            self.retransmit_timeout,
+            self.poll_interval,
+        )?;
+        // a zero timeout is refused by the tcp stream
+        at_least(
+            "handshake timeout",
+            self.handshake_timeout,
+            Duration::from_millis(1),
+        )?;
+        if let Some(threshold) = self.rendezvous {
+            at_least("rendezvous threshold", threshold, 1)?;
+        }
+        if let Some(interval) = self.config.keepalive_micros {
+            at_least("keepalive interval", interval, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Set the local tunables of a session created with the agreed config
+    pub(crate) fn apply(&self, session: &mut Session) {
+        session.set_retransmit_timeout(self.retransmit_timeout.as_micros() as u64);
+        session.set_poll_interval(self.poll_interval.as_micros() as u64);
+    }
+}
+
+/// Setters of the session tunables shared by both builders
+macro_rules! session_setters {
+    () => {
+        /// Codecs the sessions may use, the preferred one first
+        pub fn codecs(mut self, codecs: &[CodecKind]) -> Self {
+            self.session.codecs = codecs.to_vec();
+            self
+        }
+
+        /// All of the parameters agreed on with the remote end at once
+        pub fn session_config(mut self, config: SessionConfig) -> Self {
+            self.session.config = config;
+            self
+        }
+
+        /// Packets a session may have unacknowledged at a time
+        pub fn window_size(mut self, window_size: usize) -> Self {
+            self.session.config.window_size = window_size;
+            self
+        }
+
+        /// The largest mtu packets are sized for
+        pub fn mtu(mut self, mtu: u64) -> Self {
+            self.session.config.mtu = mtu;
+            self
+        }
+
+        /// Follow every message with a crc32 of it
+        pub fn checksum(mut self, checksum: bool) -> Self {
+            self.session.config.checksum = checksum;
+            self
+        }
+
+        /// The largest message a session sends or receives
+        pub fn max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+            self.session.config.max_message_bytes = max_message_bytes;
+            self
+        }
+
+        /// How often a session waiting for packets tells the remote end it's alive
+        pub fn keepalive(mut self, interval: Duration) -> Self {
+            self.session.config.keepalive_micros = Some(interval.as_micros() as u64);
+            self
+        }
+
+        /// Send slots of a qp, and recv buffers posted to it
+        pub fn pool_size(mut self, pool_size: usize) -> Self {
+            self.session.qp.pool_size = pool_size;
+            self
+        }
+
+        /// Back the send slots and recv buffers of the qps with huge pages if there are any,
+        /// a shared recv pool has a setting of its own
+        pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+            self.session.qp.huge_pages = huge_pages;
+            self
+        }
+
+        /// The entry of the gid table of the port that addresses the qps
+        pub fn gid_index(mut self, gid_index: usize) -> Self {
+            self.session.qp.gid_index = gid_index;
+            self
+        }
+
+        /// How long a session goes without acks before it resends its window
+        pub fn retransmit_timeout(mut self, timeout: Duration) -> Self {
+            self.session.retransmit_timeout = timeout;
+            self
+        }
+
+        /// How long a session waits for acks at most in one round
+        pub fn poll_interval(mut self, interval: Duration) -> Self {
+            self.session.poll_interval = interval;
+            self
+        }
+
+        /// How long to wait for the remote end during the handshake
+        pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+            self.session.handshake_timeout = timeout;
+            self
+        }
+
+        /// Let the remote end pull messages of at least `threshold` bytes with RDMA READ,
+        /// instead of receiving them packet by packet
+        ///
+        /// A session uses rendezvous only if both ends turn it on, a UD session then connects
+        /// an RC qp of its own for the reads. It's off by default.
+        pub fn rendezvous(mut self, threshold: usize) -> Self {
+            self.session.rendezvous = Some(threshold);
+            self
+        }
+
+        /// Log at `level` to stdout, unless the application installed a tracing subscriber itself
+        #[cfg(feature = "log")]
+        pub fn log_level(mut self, level: Level) -> Self {
+            self.log_level = Some(level);
+            self
+        }
+    };
+}
+
+/// Sets up a [`Server`]
+pub struct ServerBuilder<T, R> {
+    pub(crate) dev: String,
+    pub(crate) addr: SocketAddrV4,
+    pub(crate) handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+    pub(crate) ib_port: u8,
+    pub(crate) mode: TransportMode,
+    pub(crate) qps: usize,
+    pub(crate) shared_recv: Option<SharedRecvConfig>,
+    pub(crate) batch_workers: usize,
+    pub(crate) session: SessionSetup,
+    #[cfg(feature = "log")]
+    log_level: Option<Level>,
+}
+
+impl<T, R> ServerBuilder<T, R>
+where
+    T: DeserializeOwned + 'static + Clone + Send,
+    R: Serialize + 'static + Clone + Send,
+{
+    pub fn new(
+        dev: &str,
+        addr: SocketAddrV4,
+        handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+    ) -> Self {
+        Self {
+            dev: dev.to_string(),
+            addr,
+            handler,
+            ib_port: DEFAULT_IB_PORT,
+            mode: TransportMode::default(),
+            qps: DEFAULT_SERVER_QPS,
+            shared_recv: None,
+            batch_workers: 1,
+            session: SessionSetup::new(&CodecKind::ALL),
+            #[cfg(feature = "log")]
+            log_level: None,
+        }
+    }
+
+    pub fn ib_port(mut self, ib_port: u8) -> Self {
+        self.ib_port = ib_port;
+        self
+    }
+
+    /// Serve clients in `mode`
+    pub fn mode(mut self, mode: TransportMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Spread sessions over `qps` shared UD qps
+    pub fn qps(mut self, qps: usize) -> Self {
+        self.qps = qps;
+        self
+    }
+
+    /// Let the shared UD qps borrow recv buffers from one pool
+    pub fn shared_recv(mut self, config: SharedRecvConfig) -> Self {
+        self.shared_recv = Some(config);
+        self
+    }
+
+    /// Handle the requests of a batch on up to `workers` threads
+    pub fn batch_workers(mut self, workers: usize) -> Self {
+        self.batch_workers = workers;
+        self
+    }
+
+    session_setters!();
+
+    pub fn build(self) -> Result<Server<T, R>, ServerError> {
+        self.validate()?;
+        #[cfg(feature = "log")]
+        init_logging(self.log_level);
+        Server::create(self)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.session.validate()?;
+        at_least("batch workers", self.batch_workers, 1)?;
+        match self.mode {
+            TransportMode::Ud => at_least("server qps", self.qps, 1)?,
+            TransportMode::Rc if self.shared_recv.is_some() => {
+                return Err(ConfigError::Conflict(
+                    "a shared recv pool only backs UD qps, an rc server has none".to_string(),
+                ))
+            }
+            TransportMode::Rc => {}
+        }
+        Ok(())
+    }
+}
+
+/// Sets up a [`Client`]
+pub struct ClientBuilder<T, R> {
+    pub(crate) dev: String,
+    pub(crate) addr: SocketAddrV4,
+    pub(crate) ib_port: u8,
+    pub(crate) mode: TransportMode,
+    pub(crate) call_timeout: Option<Duration>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) idempotent: fn(&T) -> bool,
+    pub(crate) session: SessionSetup,
+    #[cfg(feature = "log")]
+    log_level: Option<Level>,
+    phantom: PhantomData<R>,
+}
+
+impl<T, R> ClientBuilder<T, R>
+where
+    T: Serialize + 'static + Clone,
+    R: DeserializeOwned + 'static + Clone,
+{
+    pub fn new(dev: &str, addr: SocketAddrV4) -> Self {
+        Self {
+            dev: dev.to_string(),
+            addr,
+            ib_port: DEFAULT_IB_PORT,
+            mode: TransportMode::default(),
+            call_timeout: None,
+            retry_policy: RetryPolicy::default(),
+            idempotent: |_| false,
+            session: SessionSetup::new(&[CodecKind::default()]),
+            #[cfg(feature = "log")]
+            log_level: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn ib_port(mut self, ib_port: u8) -> Self {
+        self.ib_port = ib_port;
+        self
+    }
+
+    /// Connect to a server that serves clients in `mode`
+    pub fn mode(mut self, mode: TransportMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Send a call again if its response doesn't arrive within `timeout`
+    pub fn call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = Some(timeout);
+        self
+    }
+
+    /// How many times and how soon a failed call is sent again
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Mark the calls for which `idempotent` returns true as safe to run more than once,
+    /// the server doesn't keep their responses for retries, and they are retried if the server fails them
+    pub fn idempotent(mut self, idempotent: fn(&T) -> bool) -> Self {
+        self.idempotent = idempotent;
+        self
+    }
+
+    session_setters!();
+
+    pub fn build(self) -> Result<Client<T, R>, ClientError> {
+        self.validate()?;
+        #[cfg(feature = "log")]
+        init_logging(self.log_level);
+        Client::connect(self)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.session.validate()?;
+        if let Some(timeout) = self.call_timeout {
+            at_least("call timeout", timeout, Duration::from_micros(1))?;
+        }
+        at_least("max attempts", self.retry_policy.max_attempts, 1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ClientBuilder, ConfigError};
+
+    #[test]
+    fn validate() {
+        let builder =
+            || ClientBuilder::<u64, u64>::new("rxe_0", "127.0.0.1:10001".parse().unwrap());
+        assert!(builder().validate().is_ok());
+        assert!(builder()
+            .window_size(128)
+            .pool_size(256)
+            .checksum(true)
+            .validate()
+            .is_ok());
+
+        let err = builder().window_size(0).validate().unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                name: "window size",
+                ..
+            }
+        ));
+        let err = builder().mtu(8192).validate().unwrap_err();
+        assert_eq!(err.to_string(), "mtu is 8192, it must be at most 4096");
+        let err = builder().pool_size(16).validate().unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                name: "pool size",
+                ..
+            }
+        ));
+        let err = builder()
+            .poll_interval(Duration::from_millis(10))
+            .retransmit_timeout(Duration::from_millis(1))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                name: "retransmit timeout",
+                ..
+            }
+        ));
+        let err = builder().codecs(&[]).validate().unwrap_err();
+        assert!(matches!(err, ConfigError::Conflict(_)));
+        let err = builder()
+            .window_size(64)
+            .pool_size(64)
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "window size 64 leaves no send slots for acks in a pool of 64"
+        );
+    }
+}
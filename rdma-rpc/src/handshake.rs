@@ -0,0 +1,151 @@
+//! The frames a client and a server exchange over TCP to set up a session
+//!
+//! Each side sends one frame: the magic, the protocol version, the capabilities of the sender
+//! and the length of the body, all big endian, followed by the bincode of the body.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+/// Starts every frame, anything else on the port isn't an rdma-rpc peer
+pub const MAGIC: [u8; 4] = *b"RRPC";
+/// Bumped whenever the frames or their bodies change
+pub const PROTOCOL_VERSION: u16 = 3;
+/// How long a side waits for the frame of the other side
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bodies are small, a longer one is taken as garbage rather than allocated
+const MAX_BODY_BYTES: u32 = 64 * 1024;
+const HEADER_BYTES: usize = 4 + 2 + 4 + 4;
+
+/// What the sender of a frame supports
+pub mod capability {
+    /// sessions over the shared UD qps of the server
+    pub const UD: u32 = 1 << 0;
+    /// sessions over an RC qp of their own
+    pub const RC: u32 = 1 << 1;
+    /// large messages pulled over a rendezvous channel, optional
+    pub const RENDEZVOUS: u32 = 1 << 2;
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("handshake io error, {0}")]
+    Io(String),
+    #[error("peer didn't finish the handshake in time")]
+    Timeout,
+    #[error("peer isn't an rdma-rpc peer, its frame starts with {0:?}")]
+    BadMagic([u8; 4]),
+    #[error("peer speaks protocol version {theirs}, this side speaks version {ours}")]
+    VersionMismatch { ours: u16, theirs: u16 },
+    #[error("frame of {0} bytes is larger than {MAX_BODY_BYTES} bytes")]
+    TooLarge(u32),
+    #[error("failed to decode the frame, {0}")]
+    Decode(String),
+    #[error("peer refused the session, {0}")]
+    Refused(String),
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(err.to_string()),
+        }
+    }
+}
+
+/// Give up on a peer that stalls the handshake for longer than `timeout`
+pub(crate) fn set_timeout(stream: &TcpStream, timeout: Duration) -> Result<(), HandshakeError> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(())
+}
+
+/// Send a frame carrying `body`
+pub(crate) fn send<T: Serialize>(
+    stream: &mut impl Write,
+    capabilities: u32,
+    body: &T,
+) -> Result<(), HandshakeError> {
+    let body = bincode::serialize(body).map_err(|err| HandshakeError::Decode(err.to_string()))?;
+    let mut frame = Vec::with_capacity(HEADER_BYTES + body.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.extend_from_slice(&capabilities.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+/// Receive a frame, returns the capabilities of the sender and the body
+///
+/// The body of a frame of another protocol version is skipped, since it may not decode.
+pub(crate) fn recv<T: DeserializeOwned>(
+    stream: &mut impl Read,
+) -> Result<(u32, T), HandshakeError> {
+    let mut header = [0; HEADER_BYTES];
+    stream.read_exact(&mut header)?;
+    let magic: [u8; 4] = header[0..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(HandshakeError::BadMagic(magic));
+    }
+    let version = u16::from_be_bytes(header[4..6].try_into().unwrap());
+    let capabilities = u32::from_be_bytes(header[6..10].try_into().unwrap());
+    let len = u32::from_be_bytes(header[10..14].try_into().unwrap());
+    if len > MAX_BODY_BYTES {
+        return Err(HandshakeError::TooLarge(len));
+    }
+
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body)?;
+    if version != PROTOCOL_VERSION {
+        return Err(HandshakeError::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs: version,
+        });
+    }
+    let body =
+        bincode::deserialize(&body).map_err(|err| HandshakeError::Decode(err.to_string()))?;
+    Ok((capabilities, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{capability, recv, send, HandshakeError, MAGIC};
+
+    #[test]
+    fn frames() {
+        let mut buf = Vec::new();
+        send(&mut buf, capability::UD, &(7u64, "hello".to_string())).unwrap();
+        let (capabilities, body): (u32, (u64, String)) = recv(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(capabilities, capability::UD);
+        assert_eq!(body, (7, "hello".to_string()));
+
+        // a truncated frame fails rather than decoding half of it
+        let err = recv::<(u64, String)>(&mut Cursor::new(&buf[..buf.len() - 1])).unwrap_err();
+        assert!(matches!(err, HandshakeError::Io(_)));
+
+        // another version is told apart before its body is decoded
+        let mut other = buf.clone();
+        other[4..6].copy_from_slice(&4u16.to_be_bytes());
+        other[10..14].copy_from_slice(&0u32.to_be_bytes());
+        let err = recv::<(u64, String)>(&mut Cursor::new(&other)).unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::VersionMismatch { ours: 3, theirs: 4 }
+        ));
+
+        let mut garbage = buf.clone();
+        garbage[0..4].copy_from_slice(b"GET ");
+        let err = recv::<(u64, String)>(&mut Cursor::new(&garbage)).unwrap_err();
+        assert!(matches!(err, HandshakeError::BadMagic(magic) if magic != MAGIC));
+    }
+}
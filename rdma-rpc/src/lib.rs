@@ -1,37 +1,99 @@
 extern crate alloc;
 
+pub mod builder;
+pub mod handshake;
+
 use alloc::sync::Arc;
 use std::{
-    io::{Read, Write},
     marker::PhantomData,
     net::{SocketAddrV4, TcpListener, TcpStream},
     thread,
 };
 
+use builder::SessionSetup;
+pub use builder::{ClientBuilder, ConfigError, ServerBuilder};
+use handshake::{capability, HandshakeError};
 use rdma_rpc_core::{
-    client_stub::ClientStub,
+    client_stub::{CallOptions, ClientStub},
+    codec::CodecKind,
+    context::{Metadata, PeerInfo},
+    demux::SharedTransport,
+    interceptor::{ClientChain, ClientInterceptor, ServerChain, ServerInterceptor},
     messages::QPInfo,
+    rc::{PreparedRc, RcInfo},
+    recv_pool::{SharedRecvConfig, SharedRecvPool},
+    retry::RetryPolicy,
     server_stub::{RpcHandler, ServerStub},
-    session::Session,
-    transport::Transport,
+    session::{Session, SessionConfig},
+    streaming::StreamCall,
+    transport::{new_ud_qp, query_mtu, Transport},
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{error, info};
-use KRdmaKit::{context::Context, log::warn, services_user, QueuePairBuilder, UDriver};
+use KRdmaKit::{context::Context, log::warn, services_user, QueuePair, UDriver};
+
+/// Which kind of qp carries the packets of sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportMode {
+    /// Unreliable datagram, sessions ack and retransmit packets in software,
+    /// and a server serves all clients with a few shared qps
+    #[default]
+    Ud,
+    /// Reliable connection, the nic retransmits and orders packets so sessions don't ack them,
+    /// and packets are larger, at the cost of one qp per client
+    Rc,
+}
+
+/// One end of the transport of a session
+#[derive(Serialize, Deserialize)]
+enum TransportInfo {
+    Ud(QPInfo),
+    Rc(RcInfo),
+}
+
+/// Sent by a client to open a session
+#[derive(Serialize, Deserialize)]
+struct ClientInfo {
+    transport: TransportInfo,
+    /// the client's end of the rendezvous channel, if the client turned rendezvous on over UD
+    rc_info: Option<RcInfo>,
+    /// codecs the client can use, the one it prefers first
+    codecs: Vec<CodecKind>,
+    /// the config the client proposes for the session
+    config: SessionConfig,
+}
 
 #[derive(Serialize, Deserialize)]
 struct SessionInfo {
-    qp_info: QPInfo,
+    transport: TransportInfo,
     session_id: u64,
+    /// the server's end of the rendezvous channel, if both ends turned rendezvous on over UD
+    rc_info: Option<RcInfo>,
+    /// the config both ends run the session with, agreed on by the server within its limits
+    config: SessionConfig,
+    /// the codec both ends encode with, one of those the client proposed
+    codec: CodecKind,
 }
 
+/// How many well-known UD qps a server listens on by default
+pub const DEFAULT_SERVER_QPS: usize = 1;
+
 pub struct Server<T, R> {
     addr: SocketAddrV4,
-    ib_port: u8,
     context: Arc<Context>,
+    ib_port: u8,
+    mode: TransportMode,
+    /// well-known qps shared by all sessions, sessions are assigned to them round-robin
+    transports: Vec<Arc<SharedTransport>>,
     handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
     session_id: u64,
+    /// threads each session spreads the requests of a batch over
+    batch_workers: usize,
+    /// run around the handler of every session
+    interceptors: ServerChain<T, R>,
+    /// codecs clients may pick, and the limits of the configs they propose
+    session: SessionSetup,
 }
 
 #[derive(Error, Debug)]
@@ -44,12 +106,14 @@ pub enum ServerError {
     Rdma(String),
     #[error("error binding port, {0}")]
     TcpBind(String),
+    #[error("invalid config, {0}")]
+    Config(#[from] ConfigError),
 }
 
 impl<T, R> Server<T, R>
 where
-    T: DeserializeOwned + 'static + Clone,
-    R: Serialize + 'static + Clone,
+    T: DeserializeOwned + 'static + Clone + Send,
+    R: Serialize + 'static + Clone + Send,
 {
     pub fn new(
         dev: &str,
@@ -57,6 +121,70 @@ where
         addr: SocketAddrV4,
         handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
     ) -> Result<Server<T, R>, ServerError> {
+        ServerBuilder::new(dev, addr, handler)
+            .ib_port(ib_port)
+            .build()
+    }
+
+    /// Create a server whose sessions are spread over `n_qps` shared UD qps
+    pub fn new_with_qps(
+        dev: &str,
+        ib_port: u8,
+        addr: SocketAddrV4,
+        handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+        n_qps: usize,
+    ) -> Result<Server<T, R>, ServerError> {
+        ServerBuilder::new(dev, addr, handler)
+            .ib_port(ib_port)
+            .qps(n_qps)
+            .build()
+    }
+
+    /// Create a server that serves clients in `mode`
+    pub fn new_with_mode(
+        dev: &str,
+        ib_port: u8,
+        addr: SocketAddrV4,
+        handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+        mode: TransportMode,
+    ) -> Result<Server<T, R>, ServerError> {
+        ServerBuilder::new(dev, addr, handler)
+            .ib_port(ib_port)
+            .mode(mode)
+            .build()
+    }
+
+    /// Create a server whose `n_qps` shared UD qps all borrow recv buffers from one shared pool
+    pub fn new_with_shared_recv(
+        dev: &str,
+        ib_port: u8,
+        addr: SocketAddrV4,
+        handler: Arc<dyn RpcHandler<Args = T, Resp = R>>,
+        n_qps: usize,
+        recv_config: SharedRecvConfig,
+    ) -> Result<Server<T, R>, ServerError> {
+        ServerBuilder::new(dev, addr, handler)
+            .ib_port(ib_port)
+            .qps(n_qps)
+            .shared_recv(recv_config)
+            .build()
+    }
+
+    /// Create the server set up by `builder`, which is validated already
+    fn create(builder: ServerBuilder<T, R>) -> Result<Server<T, R>, ServerError> {
+        let ServerBuilder {
+            dev,
+            addr,
+            handler,
+            ib_port,
+            mode,
+            qps,
+            shared_recv,
+            batch_workers,
+            session,
+            ..
+        } = builder;
+
         // create context
         let context = {
             let udriver = UDriver::create().ok_or(ServerError::NoDevice)?;
@@ -64,20 +192,72 @@ where
                 .devices()
                 .iter()
                 .find(|d| d.name() == dev)
-                .ok_or_else(|| ServerError::NoSuchDevice(dev.to_string()))?;
+                .ok_or_else(|| ServerError::NoSuchDevice(dev.clone()))?;
             device
                 .open_context()
                 .map_err(|e| ServerError::Rdma(e.to_string()))?
         };
+
+        // create the well-known qps, an rc server connects a qp to each client instead
+        let n_qps = match mode {
+            TransportMode::Ud => qps,
+            TransportMode::Rc => 0,
+        };
+        let recv_pool = shared_recv
+            .map(|config| SharedRecvPool::new(Arc::clone(&context), ib_port, config))
+            .transpose()
+            .map_err(|e| ServerError::Rdma(e.to_string()))?;
+        let transports = (0..n_qps)
+            .map(|_| {
+                SharedTransport::new_with_config(
+                    Arc::clone(&context),
+                    ib_port,
+                    session.qp,
+                    recv_pool.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ServerError::Rdma(e.to_string()))?;
+        for transport in transports.iter() {
+            info!("server listens on qp {}", transport.qp_info());
+        }
+
         Ok(Self {
             addr,
-            ib_port,
             context,
+            ib_port,
+            mode,
+            transports,
             handler,
             session_id: 0,
+            batch_workers,
+            interceptors: ServerChain::default(),
+            session,
         })
     }
 
+    /// Handle the requests of a batch on up to `workers` threads, by default they are handled in turn
+    pub fn set_batch_workers(&mut self, workers: usize) {
+        self.batch_workers = workers;
+    }
+
+    /// Accept only sessions that use one of `codecs`, by default all of them are accepted
+    pub fn set_codecs(&mut self, codecs: Vec<CodecKind>) {
+        self.session.codecs = codecs;
+    }
+
+    /// Cap the configs clients propose for their sessions at `config`
+    pub fn set_session_config(&mut self, config: SessionConfig) {
+        self.session.config = config;
+    }
+
+    /// Run `interceptor` around the handler of every session, after the interceptors added before it
+    ///
+    /// It sees unary calls, the requests of batches and notifications, but not streaming calls.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn ServerInterceptor<T, R>>) {
+        self.interceptors.push(interceptor);
+    }
+
     pub fn serve(mut self) -> Result<(), ServerError> {
         info!("server start listening on {}", self.addr);
         let listener =
@@ -96,68 +276,225 @@ where
     }
 
     pub fn handle_client(&mut self, mut stream: TcpStream) {
-        let context = Arc::clone(&self.context);
-        let ib_port = self.ib_port;
         let handler = Arc::clone(&self.handler);
         // create a new session
         let session_id = self.session_id;
         self.session_id += 1;
+        let shared = (!self.transports.is_empty())
+            .then(|| Arc::clone(&self.transports[session_id as usize % self.transports.len()]));
+        let context = Arc::clone(&self.context);
+        let ib_port = self.ib_port;
+        let mode = self.mode;
+        let batch_workers = self.batch_workers;
+        let interceptors = self.interceptors.clone();
+        let setup = self.session.clone();
         thread::spawn(move || {
-            // receive QPInfo from stream
-            let mut buf = [0; 1024];
-            let size = match stream.read(&mut buf) {
-                Ok(size) => size,
-                Err(err) => {
-                    warn!("failed to read qp info from client, {err}");
-                    warn!("closing session {session_id}");
-                    return;
-                }
-            };
-            let client_qp_info: QPInfo = match bincode::deserialize(&buf[0..size]) {
-                Ok(qp_info) => qp_info,
+            let accepted = accept(
+                &mut stream,
+                session_id,
+                mode,
+                shared,
+                context,
+                ib_port,
+                &setup,
+            );
+            let (session, peer) = match accepted {
+                Ok(accepted) => accepted,
                 Err(err) => {
-                    warn!("failed to deserialize client qp info, {err}");
+                    warn!("{err}");
                     warn!("closing session {session_id}");
                     return;
                 }
             };
-            info!("client qp info: {client_qp_info}");
-
-            // create transport and self qp_info
-            let transport = match Transport::new(context, client_qp_info, ib_port) {
-                Ok(transport) => transport,
-                Err(e) => {
-                    warn!("failed to create transport for client, {e}");
-                    warn!("closing session {session_id}");
-                    return;
-                }
-            };
-            let qp_info = transport.qp_info();
-
-            // send back self info
-            info!("server qp info: {qp_info}, session_id: {session_id}");
-            let session_info = SessionInfo {
-                qp_info,
-                session_id,
-            };
-            let session_info = bincode::serialize(&session_info).unwrap();
-            if let Err(err) = stream.write_all(&session_info) {
-                warn!("failed to send session info to the client, {err}");
-            }
 
             // start serving
-            let session = Session::new(session_id, transport);
-            let server_stub = ServerStub::new(session, handler);
+            let mut server_stub = ServerStub::new(session, handler);
+            server_stub.set_batch_workers(batch_workers);
+            server_stub.set_peer(peer);
+            server_stub.set_interceptors(interceptors);
             info!("session {session_id} start serving");
             server_stub.serve()
         });
     }
 }
 
+/// The capabilities a side announces in its handshake frame
+fn capabilities(mode: TransportMode, rendezvous: bool) -> u32 {
+    let mode = match mode {
+        TransportMode::Ud => capability::UD,
+        TransportMode::Rc => capability::RC,
+    };
+    if rendezvous {
+        mode | capability::RENDEZVOUS
+    } else {
+        mode
+    }
+}
+
+/// Exchange session info with a client over `stream`, and create the session
+///
+/// A client whose handshake fails is told why before the stream is closed.
+fn accept(
+    stream: &mut TcpStream,
+    session_id: u64,
+    mode: TransportMode,
+    shared: Option<Arc<SharedTransport>>,
+    context: Arc<Context>,
+    ib_port: u8,
+    setup: &SessionSetup,
+) -> Result<(Session, PeerInfo), String> {
+    handshake::set_timeout(stream, setup.handshake_timeout)
+        .map_err(|err| format!("failed to set handshake timeout, {err}"))?;
+    let accepted = handshake::recv(stream)
+        .map_err(|err| format!("bad handshake from client, {err}"))
+        .and_then(|(client_capabilities, client_info)| {
+            open_session(
+                stream,
+                client_capabilities,
+                client_info,
+                session_id,
+                mode,
+                shared,
+                context,
+                ib_port,
+                setup,
+            )
+        });
+
+    // send back self info, or why the session is refused
+    let reply = accepted
+        .as_ref()
+        .map(|(_, _, session_info)| session_info)
+        .map_err(|reason| reason.clone());
+    let ours = capabilities(mode, setup.rendezvous.is_some());
+    let sent = handshake::send(stream, ours, &reply)
+        .map_err(|err| format!("failed to send session info to the client, {err}"));
+    let (session, peer, _) = accepted?;
+    sent?;
+    info!("server sent session info, session_id: {session_id}");
+    Ok((session, peer))
+}
+
+/// Create the session asked for by `client_info`
+#[allow(clippy::too_many_arguments)]
+fn open_session(
+    stream: &TcpStream,
+    client_capabilities: u32,
+    client_info: ClientInfo,
+    session_id: u64,
+    mode: TransportMode,
+    shared: Option<Arc<SharedTransport>>,
+    context: Arc<Context>,
+    ib_port: u8,
+    setup: &SessionSetup,
+) -> Result<(Session, PeerInfo, SessionInfo), String> {
+    // rendezvous is optional, only the transport mode must match
+    let required = capabilities(mode, false);
+    let missing = required & !client_capabilities;
+    if missing != 0 {
+        return Err(format!(
+            "server serves clients in mode {mode:?} with capabilities {required:#x}, the client lacks {missing:#x}"
+        ));
+    }
+    let codec = CodecKind::negotiate(&client_info.codecs, &setup.codecs).ok_or_else(|| {
+        format!(
+            "client proposed codecs {:?}, none of which is accepted",
+            client_info.codecs
+        )
+    })?;
+    let config = setup
+        .config
+        .agree(&client_info.config)
+        .map_err(|err| format!("client proposed a bad session config, {err}"))?;
+
+    let (lid, qp_num) = match &client_info.transport {
+        TransportInfo::Ud(info) => (info.lid, info.qp_num),
+        TransportInfo::Rc(info) => (info.lid, info.qp_num),
+    };
+    let peer = PeerInfo {
+        addr: stream.peer_addr().ok(),
+        lid,
+        qp_num,
+    };
+
+    // rendezvous is on if both ends turned it on
+    let threshold = setup
+        .rendezvous
+        .filter(|_| client_capabilities & capability::RENDEZVOUS != 0);
+
+    // create the transport of the session
+    let (transport, mut session): (TransportInfo, Session) = match (client_info.transport, shared) {
+        (TransportInfo::Ud(client_qp_info), Some(shared)) => {
+            info!("client qp info: {client_qp_info}");
+            // register the session on the shared qp
+            let transport = shared
+                .open_session(session_id, client_qp_info)
+                .map_err(|e| format!("failed to create transport for client, {e}"))?;
+            (
+                TransportInfo::Ud(shared.qp_info()),
+                Session::with_config(session_id, transport, config.clone()),
+            )
+        }
+        (TransportInfo::Rc(client_rc_info), None) => {
+            let rc = PreparedRc::new_with_config(Arc::clone(&context), ib_port, &setup.qp)
+                .map_err(|e| format!("failed to create rc qp, {e}"))?;
+            let rc_info = rc.info();
+            let transport = rc
+                .into_transport(client_rc_info)
+                .map_err(|e| format!("failed to connect rc qp to client, {e}"))?;
+            // an rc session reads over its own qp, there is no channel to connect
+            let channel = threshold.map(|_| transport.rendezvous_channel());
+            let mut session = Session::with_config(session_id, transport, config.clone());
+            if let (Some(channel), Some(threshold)) = (channel, threshold) {
+                session.enable_rendezvous(channel, threshold);
+            }
+            (TransportInfo::Rc(rc_info), session)
+        }
+        _ => return Err(format!("client doesn't use the transport mode {mode:?}")),
+    };
+
+    // a UD session reads through an auxiliary rc qp, connected to the one of the client
+    let rc_info = match (threshold, client_info.rc_info) {
+        (Some(threshold), Some(client_rc_info)) => {
+            let rc = PreparedRc::new_with_config(context, ib_port, &setup.qp)
+                .map_err(|e| format!("failed to create rendezvous channel, {e}"))?;
+            let rc_info = rc.info();
+            let channel = rc
+                .connect(client_rc_info)
+                .map_err(|e| format!("failed to connect rendezvous channel, {e}"))?;
+            session.enable_rendezvous(channel, threshold);
+            Some(rc_info)
+        }
+        _ => None,
+    };
+    session.set_codec(codec);
+    setup.apply(&mut session);
+
+    info!("server opens session {session_id}, config: {config:?}, codec: {codec:?}");
+    let session_info = SessionInfo {
+        transport,
+        session_id,
+        rc_info,
+        config,
+        codec,
+    };
+    Ok((session, peer, session_info))
+}
+
+/// The qp of a client before the server replies
+enum ClientQp {
+    Ud(Arc<QueuePair>),
+    Rc(PreparedRc),
+}
+
 pub struct Client<T, R> {
     client_stub: ClientStub,
     #[allow(unused)] // Reserve for future usage
     context: Arc<Context>,
+    /// tells whether the call of some args may run more than once
+    idempotent: fn(&T) -> bool,
+    /// run around every call sent
+    interceptors: ClientChain<T, R>,
     phantom_t: PhantomData<T>,
     phantom_r: PhantomData<R>,
 }
@@ -172,6 +509,14 @@ pub enum ClientError {
     Rdma(String),
     #[error("connect failed, {0}")]
     Connect(String),
+    #[error("remote call failed, {0}")]
+    Remote(String),
+    #[error("call rejected, {0}")]
+    Rejected(String),
+    #[error("handshake failed, {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error("invalid config, {0}")]
+    Config(#[from] ConfigError),
 }
 
 impl<T, R> Client<T, R>
@@ -180,6 +525,70 @@ where
     R: DeserializeOwned + 'static + Clone,
 {
     pub fn new(dev: &str, addr: SocketAddrV4, ib_port: u8) -> Result<Client<T, R>, ClientError> {
+        ClientBuilder::new(dev, addr).ib_port(ib_port).build()
+    }
+
+    /// Connect to a server that serves clients in `mode`
+    pub fn new_with_mode(
+        dev: &str,
+        addr: SocketAddrV4,
+        ib_port: u8,
+        mode: TransportMode,
+    ) -> Result<Client<T, R>, ClientError> {
+        ClientBuilder::new(dev, addr)
+            .ib_port(ib_port)
+            .mode(mode)
+            .build()
+    }
+
+    /// Connect to a server and encode calls with the first of `codecs` the server accepts
+    pub fn new_with_codecs(
+        dev: &str,
+        addr: SocketAddrV4,
+        ib_port: u8,
+        mode: TransportMode,
+        codecs: &[CodecKind],
+    ) -> Result<Client<T, R>, ClientError> {
+        ClientBuilder::new(dev, addr)
+            .ib_port(ib_port)
+            .mode(mode)
+            .codecs(codecs)
+            .build()
+    }
+
+    /// Connect to a server and propose `config` for the session and `codecs` to encode calls with
+    ///
+    /// The server may lower the limits of `config`, the session runs with the config it agrees on.
+    pub fn new_with_config(
+        dev: &str,
+        addr: SocketAddrV4,
+        ib_port: u8,
+        mode: TransportMode,
+        codecs: &[CodecKind],
+        config: SessionConfig,
+    ) -> Result<Client<T, R>, ClientError> {
+        ClientBuilder::new(dev, addr)
+            .ib_port(ib_port)
+            .mode(mode)
+            .codecs(codecs)
+            .session_config(config)
+            .build()
+    }
+
+    /// Connect to the server as set up by `builder`, which is validated already
+    fn connect(builder: ClientBuilder<T, R>) -> Result<Client<T, R>, ClientError> {
+        let ClientBuilder {
+            dev,
+            addr,
+            ib_port,
+            mode,
+            call_timeout,
+            retry_policy,
+            idempotent,
+            session: setup,
+            ..
+        } = builder;
+
         // create context
         let context = {
             let udriver = UDriver::create().ok_or(ClientError::NoDevice)?;
@@ -187,67 +596,252 @@ where
                 .devices()
                 .iter()
                 .find(|d| d.name() == dev)
-                .ok_or_else(|| ClientError::NoSuchDevice(dev.to_string()))?;
+                .ok_or_else(|| ClientError::NoSuchDevice(dev.clone()))?;
             device
                 .open_context()
                 .map_err(|e| ClientError::Rdma(e.to_string()))?
         };
 
         // create qp
-        let qp = QueuePairBuilder::new(&context)
-            .build_ud()
-            .map_err(|err| ClientError::Rdma(format!("failed to build ud, {err}")))?
-            .bring_up_ud()
-            .map_err(|err| ClientError::Rdma(format!("failed to bring up ud, {err}")))?;
-
-        // send self qp info
-        let client_qp_info = QPInfo {
-            lid: qp.lid().unwrap(),
-            gid: services_user::ibv_gid_wrapper::from(qp.gid().unwrap()),
-            qp_num: qp.qp_num(),
-            qkey: qp.qkey(),
+        let (qp, client_transport) = match mode {
+            TransportMode::Ud => {
+                let qp = new_ud_qp(&context, &setup.qp)
+                    .map_err(|err| ClientError::Rdma(err.to_string()))?;
+                let mtu = query_mtu(&context, ib_port)
+                    .map_err(|err| ClientError::Rdma(err.to_string()))?;
+                let client_qp_info = QPInfo {
+                    lid: qp.lid().unwrap(),
+                    gid: services_user::ibv_gid_wrapper::from(qp.gid().unwrap()),
+                    qp_num: qp.qp_num(),
+                    qkey: qp.qkey(),
+                    mtu,
+                };
+                info!("client send self qp info: {client_qp_info}");
+                (ClientQp::Ud(qp), TransportInfo::Ud(client_qp_info))
+            }
+            TransportMode::Rc => {
+                let rc = PreparedRc::new_with_config(Arc::clone(&context), ib_port, &setup.qp)
+                    .map_err(|err| ClientError::Rdma(err.to_string()))?;
+                let rc_info = rc.info();
+                (ClientQp::Rc(rc), TransportInfo::Rc(rc_info))
+            }
+        };
+
+        // send self info, with the end of a rendezvous channel if rendezvous is turned on over UD,
+        // an rc session reads over its own qp instead
+        let rc = setup
+            .rendezvous
+            .filter(|_| mode == TransportMode::Ud)
+            .map(|_| PreparedRc::new_with_config(Arc::clone(&context), ib_port, &setup.qp))
+            .transpose()
+            .map_err(|err| ClientError::Rdma(err.to_string()))?;
+        let client_info = ClientInfo {
+            transport: client_transport,
+            rc_info: rc.as_ref().map(PreparedRc::info),
+            codecs: setup.codecs.clone(),
+            config: setup.config.clone(),
         };
-        info!("client send self qp info: {client_qp_info}");
         let mut stream =
             TcpStream::connect(addr).map_err(|err| ClientError::Connect(err.to_string()))?;
-        let data = bincode::serialize(&client_qp_info).unwrap();
-        stream.write_all(&data).map_err(|err| {
-            ClientError::Connect(format!("failed to send self qp_info to the server, {err}"))
-        })?;
+        handshake::set_timeout(&stream, setup.handshake_timeout)?;
+        let ours = capabilities(mode, setup.rendezvous.is_some());
+        handshake::send(&mut stream, ours, &client_info)?;
 
         // receive session info
-        let mut buf = [0; 1024];
-        let size = stream.read(&mut buf).map_err(|err| {
-            ClientError::Connect(format!("failed to recv session info from server, {err}"))
-        })?;
+        let (theirs, reply): (u32, Result<SessionInfo, String>) = handshake::recv(&mut stream)?;
+        let threshold = setup
+            .rendezvous
+            .filter(|_| theirs & capability::RENDEZVOUS != 0);
         let SessionInfo {
-            qp_info,
+            transport,
             session_id,
-        } = bincode::deserialize(&buf[0..size]).map_err(|err| {
-            ClientError::Connect(format!("failed to deserialize session info, {err}"))
-        })?; // TODO: handle error
-        info!("client recv server qp info: {qp_info}, session id: {session_id}");
+            rc_info,
+            config,
+            codec,
+        } = reply.map_err(HandshakeError::Refused)?;
+        info!("client recv session id: {session_id}, config: {config:?}, codec: {codec:?}");
+        // the server may only lower the limits proposed, and pick one of the codecs proposed
+        setup.config.check_agreed(&config).map_err(|err| {
+            ClientError::Connect(format!("server agreed on a bad session config, {err}"))
+        })?;
+        if !setup.codecs.contains(&codec) {
+            return Err(ClientError::Connect(format!(
+                "server picked codec {codec:?}, which wasn't proposed"
+            )));
+        }
 
         // create client stub
-        let tranport = Transport::new_with_qp(qp, Arc::clone(&context), qp_info, ib_port).unwrap();
-        let session = Session::new(session_id, tranport);
-        let client_stub = ClientStub::new(session);
+        let mut session = match (qp, transport) {
+            (ClientQp::Ud(qp), TransportInfo::Ud(qp_info)) => {
+                info!("client recv server qp info: {qp_info}");
+                let tranport = Transport::new_with_qp_config(
+                    qp,
+                    Arc::clone(&context),
+                    qp_info,
+                    ib_port,
+                    &setup.qp,
+                )
+                .map_err(|err| ClientError::Rdma(err.to_string()))?;
+                Session::with_config(session_id, tranport, config)
+            }
+            (ClientQp::Rc(rc), TransportInfo::Rc(rc_info)) => {
+                let transport = rc
+                    .into_transport(rc_info)
+                    .map_err(|err| ClientError::Rdma(err.to_string()))?;
+                let channel = threshold.map(|_| transport.rendezvous_channel());
+                let mut session = Session::with_config(session_id, transport, config);
+                if let (Some(channel), Some(threshold)) = (channel, threshold) {
+                    session.enable_rendezvous(channel, threshold);
+                }
+                session
+            }
+            _ => {
+                return Err(ClientError::Connect(format!(
+                    "server doesn't use the transport mode {mode:?}"
+                )))
+            }
+        };
+        // the server answers with its end of the channel only if it turned rendezvous on too
+        if let (Some(rc), Some(rc_info), Some(threshold)) = (rc, rc_info, threshold) {
+            let channel = rc
+                .connect(rc_info)
+                .map_err(|err| ClientError::Rdma(err.to_string()))?;
+            session.enable_rendezvous(channel, threshold);
+        }
+        session.set_codec(codec);
+        setup.apply(&mut session);
+        let mut client_stub = ClientStub::new(session);
+        client_stub.set_call_timeout(call_timeout.map(|timeout| timeout.as_micros() as u64));
+        client_stub.set_retry_policy(retry_policy);
 
         Ok(Self {
             client_stub,
             context,
+            idempotent,
+            interceptors: ClientChain::default(),
             phantom_t: PhantomData,
             phantom_r: PhantomData,
         })
     }
 
+    /// Send a call again if its response doesn't arrive within `timeout_micros`,
+    /// the server runs the handler at most once for all copies of a call
+    pub fn set_call_timeout(&mut self, timeout_micros: Option<u64>) {
+        self.client_stub.set_call_timeout(timeout_micros)
+    }
+
+    /// Send a call at most `attempts` times before giving up
+    pub fn set_max_attempts(&mut self, attempts: usize) {
+        self.client_stub.set_max_attempts(attempts)
+    }
+
+    /// How many times and how soon a failed call is sent again
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.client_stub.set_retry_policy(policy)
+    }
+
+    /// Mark the calls for which `idempotent` returns true as safe to run more than once,
+    /// the server doesn't keep their responses for retries, and they are retried if the server fails them
+    pub fn set_idempotent(&mut self, idempotent: fn(&T) -> bool) {
+        self.idempotent = idempotent;
+    }
+
     pub fn send(&mut self, args: T) -> Result<R, ClientError> {
-        Ok(self.client_stub.sync_call(args)?)
+        self.send_with_metadata(args, Metadata::new())
+    }
+
+    /// Send a call along with `metadata`, which the handler finds in its request context
+    pub fn send_with_metadata(&mut self, args: T, metadata: Metadata) -> Result<R, ClientError> {
+        let idempotent = self.idempotent;
+        let client_stub = &mut self.client_stub;
+        Ok(self.interceptors.call(args, metadata, |args, metadata| {
+            let options = CallOptions {
+                idempotent: idempotent(&args),
+                metadata,
+            };
+            client_stub.sync_call_with(args, options)
+        })?)
+    }
+
+    /// Run `interceptor` around every call, every request of a batch and every notification,
+    /// after the interceptors added before it
+    ///
+    /// Streaming calls are sent without passing the interceptors.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn ClientInterceptor<T, R>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// The stub the client calls through, e.g. for a client generated by `rdma-rpc-build`
+    pub fn into_stub(self) -> ClientStub {
+        self.client_stub
+    }
+
+    /// Send many requests in one round trip, the responses are returned in the same order
+    pub fn call_batch(&mut self, args: Vec<T>) -> Result<Vec<Result<R, ClientError>>, ClientError> {
+        self.call_batch_with_metadata(args, Metadata::new())
+    }
+
+    /// Send a batch along with `metadata`, which the handler finds in the context of every request of it
+    pub fn call_batch_with_metadata(
+        &mut self,
+        args: Vec<T>,
+        metadata: Metadata,
+    ) -> Result<Vec<Result<R, ClientError>>, ClientError> {
+        let client_stub = &mut self.client_stub;
+        let resps = self
+            .interceptors
+            .call_batch(args, metadata, |args, metadata| {
+                let options = CallOptions {
+                    idempotent: false,
+                    metadata,
+                };
+                client_stub.call_batch_with(args, options)
+            })?;
+        Ok(resps
+            .into_iter()
+            .map(|resp| resp.map_err(ClientError::from))
+            .collect())
+    }
+
+    /// Send a request without waiting for a response, the server handles it with `handle_notify`
+    pub fn notify(&mut self, args: T) -> Result<(), ClientError> {
+        self.notify_with_metadata(args, Metadata::new())
+    }
+
+    /// Send a notification along with `metadata`
+    pub fn notify_with_metadata(&mut self, args: T, metadata: Metadata) -> Result<(), ClientError> {
+        let client_stub = &mut self.client_stub;
+        Ok(self.interceptors.notify(args, metadata, |args, metadata| {
+            let options = CallOptions {
+                idempotent: false,
+                metadata,
+            };
+            client_stub.notify_with(args, options)
+        })?)
+    }
+
+    /// Start a bidirectional streaming call
+    pub fn open_stream(&mut self) -> Result<StreamCall<'_, T, R>, ClientError> {
+        Ok(self.client_stub.open_stream()?)
+    }
+
+    /// Send one request and iterate over the responses as they arrive
+    pub fn send_streaming(&mut self, args: T) -> Result<StreamCall<'_, T, R>, ClientError> {
+        Ok(self.client_stub.server_streaming(args)?)
+    }
+
+    /// Upload a stream of requests and get a single response
+    pub fn send_all(&mut self, args: impl IntoIterator<Item = T>) -> Result<R, ClientError> {
+        Ok(self.client_stub.client_streaming(args)?)
     }
 }
 
 impl From<rdma_rpc_core::error::Error> for ClientError {
     fn from(err: rdma_rpc_core::error::Error) -> Self {
-        ClientError::Rdma(err.to_string())
+        match err {
+            rdma_rpc_core::error::Error::Remote(reason) => ClientError::Remote(reason),
+            rdma_rpc_core::error::Error::Rejected(reason) => ClientError::Rejected(reason),
+            err => ClientError::Rdma(err.to_string()),
+        }
     }
 }